@@ -0,0 +1,116 @@
+//! CSV的读写, 本地数据、`db build-xdb`、`db convert`及CSV输出共用
+//!
+//! 包含逗号、双引号、换行或首尾空白的字段以双引号包裹, 字段中的双引号写为`""`;
+//! 未包裹的字段去掉首尾空白, 包裹的字段保持原样
+
+use std::io::{self, BufRead};
+
+/// 转义一个字段, 包含特殊字符或首尾空白时以双引号包裹
+pub(crate) fn escape(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) || s.trim() != s {
+        return format!("\"{}\"", s.replace('"', "\"\""));
+    }
+    s.to_string()
+}
+
+/// 拆分一条记录, 引号不匹配时返回None
+pub(crate) fn split(record: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    // 是否在引号中, 当前字段是否以引号包裹
+    let mut quoted = false;
+    let mut wrapped = false;
+    let mut chars = record.chars().peekable();
+    let mut finish = |field: &mut String, wrapped: &mut bool| {
+        let field = std::mem::take(field);
+        fields.push(match std::mem::take(wrapped) {
+            true => field,
+            false => field.trim().to_string(),
+        });
+    };
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if !wrapped && field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+                wrapped = true;
+            }
+            ',' if !quoted => finish(&mut field, &mut wrapped),
+            // 忽略引号后的空白
+            c if wrapped && !quoted && c.is_whitespace() => {}
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    finish(&mut field, &mut wrapped);
+    Some(fields)
+}
+
+/// 逐条读取记录, 返回起始行号及内容, 引号中的换行属于同一条记录
+pub(crate) fn records<R: BufRead>(input: R) -> impl Iterator<Item = io::Result<(usize, String)>> {
+    let mut lines = input.lines().enumerate();
+    std::iter::from_fn(move || {
+        let (i, mut record) = match lines.next()? {
+            (i, Ok(line)) => (i, line),
+            (_, Err(e)) => return Some(Err(e)),
+        };
+        // 引号不匹配时拼接下一行, 直到文件结束
+        while record.matches('"').count() % 2 == 1 {
+            match lines.next() {
+                Some((_, Ok(line))) => {
+                    record.push('\n');
+                    record.push_str(&line);
+                }
+                Some((_, Err(e))) => return Some(Err(e)),
+                None => break,
+            }
+        }
+        Some(Ok((i + 1, record)))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        for (s, expected) in [
+            ("a, b ,c", vec!["a", "b", "c"]),
+            ("a,\"b,\"\"c\"\"\"", vec!["a", "b,\"c\""]),
+            ("\" a \" ,", vec![" a ", ""]),
+            ("a,\"\"", vec!["a", ""]),
+            ("\"a\nb\"", vec!["a\nb"]),
+        ] {
+            assert_eq!(split(s).unwrap(), expected);
+        }
+        assert!(split("a,\"b").is_none());
+        // 转义后拆分得到原内容
+        let fields = [" a", "b,c", "\"d\"", "e\r\nf", ""];
+        let record = fields.iter().map(|x| escape(x)).collect::<Vec<_>>();
+        assert_eq!(split(&record.join(",")).unwrap(), fields);
+    }
+
+    #[test]
+    fn test_records() {
+        let input = "a,b\n\"c\nd\",e\n\"f";
+        let records = records(input.as_bytes())
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            [
+                (1, "a,b".to_string()),
+                (2, "\"c\nd\",e".to_string()),
+                (4, "\"f".to_string())
+            ]
+        );
+    }
+}
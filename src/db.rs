@@ -0,0 +1,1040 @@
+//! 离线数据库的元数据、导出及转换
+
+use std::{
+    fs::File,
+    io::{self, BufRead, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use regex::Regex;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    csv,
+    error::LookupError,
+    ip::IpRange,
+    ip2region::{IpVersion, XdbError, XdbMaker, XdbSearcher, XDB_FILEPATH, XDB_V6_FILEPATH},
+    output::{Record, Template},
+    qqwry::{IpGeoInfo, Ipv6WryData, QQWryData, QQWryWriter, IPV6WRY_FILEPATH, QQWRY_FILEPATH},
+    util::replace_home,
+    IPRegion,
+};
+
+/// 离线数据库的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DatabaseKind {
+    /// 纯真qqwry.dat
+    Qqwry,
+    /// ZX ipv6wry.db
+    Ipv6wry,
+    /// ip2region.xdb
+    Ip2region,
+    /// ip2region_v6.xdb
+    Ip2regionV6,
+}
+
+impl DatabaseKind {
+    pub const ALL: [DatabaseKind; 4] = [
+        DatabaseKind::Qqwry,
+        DatabaseKind::Ipv6wry,
+        DatabaseKind::Ip2region,
+        DatabaseKind::Ip2regionV6,
+    ];
+
+    /// 默认路径, 与各渠道自动下载的位置一致
+    pub fn default_path(&self) -> PathBuf {
+        match self {
+            Self::Qqwry => PathBuf::from(replace_home(QQWRY_FILEPATH)),
+            Self::Ipv6wry => PathBuf::from(replace_home(IPV6WRY_FILEPATH)),
+            Self::Ip2region => XDB_FILEPATH.clone(),
+            Self::Ip2regionV6 => XDB_V6_FILEPATH.clone(),
+        }
+    }
+}
+
+/// 离线数据库的元数据
+#[derive(Debug, Clone, Serialize)]
+pub struct Metadata {
+    pub kind: DatabaseKind,
+    pub path: PathBuf,
+    /// 文件大小(字节)
+    pub size: u64,
+    /// 记录数, xdb为IP段的数量
+    pub records: usize,
+    /// 数据版本, 如纯真最后一条记录中的`纯真网络2024年10月16日IP数据`, xdb不包含数据版本
+    pub version: Option<String>,
+    /// 文件格式版本, 如xdb文件头中的`2`
+    pub format_version: Option<u16>,
+    /// 数据日期, 格式为`YYYY-MM-DD`
+    pub date: Option<String>,
+    /// 文件头中的字段
+    #[serde(serialize_with = "serialize_fields")]
+    pub header: Vec<(&'static str, String)>,
+    pub sha256: String,
+}
+
+// 按顺序序列化为JSON对象
+struct Fields<'a>(&'a [(&'static str, String)]);
+
+impl Serialize for Fields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_fields(self.0, serializer)
+    }
+}
+
+fn serialize_fields<S: Serializer>(
+    fields: &[(&'static str, String)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(fields.len()))?;
+    for (k, v) in fields {
+        map.serialize_entry(k, v)?;
+    }
+    map.end()
+}
+
+/// 读取离线数据库的元数据, 文件不存在时返回`DatabaseMissing`
+pub fn metadata<P: AsRef<Path>>(kind: DatabaseKind, path: P) -> Result<Metadata, LookupError> {
+    let path = PathBuf::from(replace_home(&path.as_ref().to_string_lossy()));
+    let missing = |_| LookupError::DatabaseMissing(path.clone());
+    let size = path.metadata().map_err(missing)?.len();
+    let sha256 = sha256(&path).map_err(missing)?;
+    let corrupt = |e: &dyn std::fmt::Display| LookupError::DatabaseCorrupt(e.to_string());
+    let (records, version, format_version, date, header) = match kind {
+        DatabaseKind::Qqwry => {
+            let data = open_qqwry(&path)?;
+            let (idx_first, idx_last) = data.index_range();
+            // 最后一条记录的地区为数据版本
+            let last = data.query(Ipv4Addr::BROADCAST).map_err(|e| corrupt(&e))?;
+            let version = non_blank(&last.area).or(non_blank(&last.country));
+            (
+                data.count(),
+                version.clone(),
+                None,
+                version.as_deref().and_then(parse_date),
+                vec![
+                    ("index_start", idx_first.to_string()),
+                    ("index_end", idx_last.to_string()),
+                ],
+            )
+        }
+        DatabaseKind::Ipv6wry => {
+            let data = open_ipv6wry(&path)?;
+            let last = data
+                .query(Ipv6Addr::from(u128::MAX))
+                .map_err(|e| corrupt(&e))?;
+            let version = non_blank(&format!("{} {}", last.country, last.area));
+            (
+                data.count(),
+                version.clone(),
+                Some(data.version()),
+                version.as_deref().and_then(parse_date),
+                vec![
+                    ("format_version", data.version().to_string()),
+                    ("offset_len", data.offset_len().to_string()),
+                    ("ip_len", data.ip_len().to_string()),
+                    ("index_start", data.index_start().to_string()),
+                ],
+            )
+        }
+        DatabaseKind::Ip2region | DatabaseKind::Ip2regionV6 => {
+            let searcher = open_xdb_kind(kind, &path)?;
+            let header = searcher.header();
+            (
+                header.count(),
+                None,
+                Some(header.version),
+                Some(format_timestamp(header.created_at as u64)),
+                vec![
+                    ("format_version", header.version.to_string()),
+                    ("index_policy", header.index_policy.to_string()),
+                    ("created_at", header.created_at.to_string()),
+                    ("start_index_ptr", header.start_index_ptr.to_string()),
+                    ("end_index_ptr", header.end_index_ptr.to_string()),
+                    ("ip_version", header.ip_version.to_string()),
+                ],
+            )
+        }
+    };
+    Ok(Metadata {
+        kind,
+        path,
+        size,
+        records,
+        version,
+        format_version,
+        date,
+        header,
+        sha256,
+    })
+}
+
+/// 导出格式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// 首行为表头
+    #[default]
+    Csv,
+    /// 每条记录一行JSON
+    Jsonl,
+}
+
+/// 按IP顺序将数据库中的所有记录写入`out`, 返回导出的记录数
+///
+/// 纯真及ZX导出`start_ip,end_ip,country,area`, xdb导出`start_ip,end_ip,region`,
+/// 字段保持数据库中的原样
+pub fn export<P: AsRef<Path>, W: Write>(
+    kind: DatabaseKind,
+    path: P,
+    format: ExportFormat,
+    out: W,
+) -> Result<usize, anyhow::Error> {
+    let path = PathBuf::from(replace_home(&path.as_ref().to_string_lossy()));
+    let mut writer = RecordWriter {
+        out,
+        format,
+        fields: Vec::new(),
+        count: 0,
+    };
+    match kind {
+        DatabaseKind::Qqwry => {
+            let data = open_qqwry(&path)?;
+            writer.header(&["country", "area"])?;
+            for r in data.records() {
+                let r = r.map_err(|e| LookupError::DatabaseCorrupt(e.to_string()))?;
+                writer.write(r.start.into(), r.end.into(), &[&r.country, &r.area])?;
+            }
+        }
+        DatabaseKind::Ipv6wry => {
+            let data = open_ipv6wry(&path)?;
+            writer.header(&["country", "area"])?;
+            for r in data.records() {
+                let r = r.map_err(|e| LookupError::DatabaseCorrupt(e.to_string()))?;
+                writer.write(r.start.into(), r.end.into(), &[&r.country, &r.area])?;
+            }
+        }
+        DatabaseKind::Ip2region | DatabaseKind::Ip2regionV6 => {
+            let searcher = open_xdb_kind(kind, &path)?;
+            writer.header(&["region"])?;
+            for r in searcher.segments() {
+                let r = r.map_err(|e| LookupError::DatabaseCorrupt(e.to_string()))?;
+                writer.write(r.start_ip, r.end_ip, &[&r.region])?;
+            }
+        }
+    }
+    writer.out.flush()?;
+    Ok(writer.count)
+}
+
+struct RecordWriter<W> {
+    out: W,
+    format: ExportFormat,
+    fields: Vec<&'static str>,
+    count: usize,
+}
+
+impl<W: Write> RecordWriter<W> {
+    // 记录的字段名, CSV输出为表头
+    fn header(&mut self, fields: &[&'static str]) -> io::Result<()> {
+        self.fields = fields.to_vec();
+        if self.format == ExportFormat::Csv {
+            writeln!(self.out, "start_ip,end_ip,{}", fields.join(","))?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, start: IpAddr, end: IpAddr, values: &[&str]) -> io::Result<()> {
+        match self.format {
+            ExportFormat::Csv => {
+                let values = values.iter().map(|x| csv::escape(x)).collect::<Vec<_>>();
+                writeln!(self.out, "{},{},{}", start, end, values.join(","))?;
+            }
+            ExportFormat::Jsonl => {
+                let mut fields = vec![("start_ip", start.to_string()), ("end_ip", end.to_string())];
+                fields.extend(
+                    self.fields
+                        .iter()
+                        .zip(values)
+                        .map(|(k, v)| (*k, v.to_string())),
+                );
+                serde_json::to_writer(&mut self.out, &Fields(&fields))?;
+                writeln!(self.out)?;
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// 从CSV生成xdb, 每行为`start_ip,end_ip,region`, 返回IP段的数量
+///
+/// 忽略空行、`#`开头的行及`start_ip`开头的表头, 与`export`导出的xdb格式一致;
+/// IP类型由第一行决定, 地区中包含逗号时可用双引号包裹
+pub fn build_xdb<R: BufRead, P: AsRef<Path>>(input: R, output: P) -> Result<usize, anyhow::Error> {
+    let mut maker: Option<XdbMaker> = None;
+    for row in csv_rows(input) {
+        let (line, start, end, region) = row?;
+        let maker = maker.get_or_insert_with(|| new_maker(start));
+        maker
+            .add(start, end, &region)
+            .map_err(|e| anyhow!("第{}行: {}", line, e))?;
+    }
+    let maker = maker.ok_or(anyhow!("没有IP段"))?;
+    maker
+        .write(output)
+        .map_err(|e| anyhow!("生成失败: {}", e))?;
+    Ok(maker.len())
+}
+
+// 逐条解析`start_ip,end_ip,region`, 返回行号及IP段
+fn csv_rows<R: BufRead>(
+    input: R,
+) -> impl Iterator<Item = Result<(usize, IpAddr, IpAddr, String), anyhow::Error>> {
+    csv::records(input).filter_map(|record| {
+        let (line, record) = match record {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let trimmed = record.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("start_ip,") {
+            return None;
+        }
+        let err = |msg: &str| anyhow!("第{}行{}: {}", line, msg, trimmed);
+        let fields = match csv::split(&record) {
+            Some(fields) => fields,
+            None => return Some(Err(err("引号不匹配"))),
+        };
+        let [start, end, region] = match <[String; 3]>::try_from(fields) {
+            Ok(fields) => fields,
+            Err(_) => return Some(Err(err("格式错误, 应为`start_ip,end_ip,region`"))),
+        };
+        match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) => Some(Ok((line, start, end, region))),
+            _ => Some(Err(err("IP格式错误"))),
+        }
+    })
+}
+
+// IP类型与第一个IP段一致
+fn new_maker(start: IpAddr) -> XdbMaker {
+    XdbMaker::new(match start {
+        IpAddr::V4(_) => IpVersion::V4,
+        IpAddr::V6(_) => IpVersion::V6,
+    })
+    .with_created_at(now())
+}
+
+/// 转换的数据格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConvertFormat {
+    /// 纯真qqwry.dat, 只支持IPv4
+    Qqwry,
+    /// ip2region xdb
+    Xdb,
+    /// `start_ip,end_ip,region`, 与`build-xdb`的输入一致
+    Csv,
+}
+
+/// 从纯真转为xdb时地区的默认模板, 缺失的字段为`0`
+pub const DEFAULT_REGION_TEMPLATE: &str = "{country:-0}|0|{province:-0}|{city:-0}|{isp:-0}";
+
+/// 转换时的字段映射, 模板字段与查询结果一致, 字段取自数据库中的原始内容
+///
+/// xdb及CSV的地区默认保持原样(从纯真转换时为`DEFAULT_REGION_TEMPLATE`),
+/// 纯真的国家默认为`国家–省份–城市`, 地区默认为ISP
+#[derive(Debug, Clone)]
+pub struct FieldMapping {
+    region: Option<Template>,
+    country: Option<Template>,
+    area: Template,
+    csv_version: Option<u16>,
+}
+
+impl Default for FieldMapping {
+    fn default() -> Self {
+        Self {
+            region: None,
+            country: None,
+            area: "{isp}".parse().unwrap(),
+            csv_version: None,
+        }
+    }
+}
+
+impl FieldMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// xdb及CSV的地区
+    pub fn with_region(mut self, template: Template) -> Self {
+        self.region = Some(template);
+        self
+    }
+
+    /// 纯真的国家
+    pub fn with_country(mut self, template: Template) -> Self {
+        self.country = Some(template);
+        self
+    }
+
+    /// 纯真的地区
+    pub fn with_area(mut self, template: Template) -> Self {
+        self.area = template;
+        self
+    }
+
+    /// 输入CSV中地区的xdb格式版本, 2为`国家|区域|省份|城市|ISP`, 3起为`国家|省份|城市|ISP`;
+    /// 默认与生成的xdb一致, IPv4为2, IPv6为3
+    pub fn with_csv_version(mut self, version: u16) -> Self {
+        self.csv_version = Some(version);
+        self
+    }
+
+    fn map(&self, from: ConvertFormat, to: ConvertFormat, region: &IPRegion) -> Vec<String> {
+        let record = Record::new("", region);
+        match to {
+            ConvertFormat::Xdb | ConvertFormat::Csv => {
+                let region = match (&self.region, from) {
+                    (Some(t), _) => t.render_raw(&record),
+                    (None, ConvertFormat::Qqwry) => DEFAULT_REGION_TEMPLATE
+                        .parse::<Template>()
+                        .unwrap()
+                        .render_raw(&record),
+                    (None, _) => region.region.clone(),
+                };
+                vec![region]
+            }
+            ConvertFormat::Qqwry => {
+                let country = match &self.country {
+                    Some(t) => t.render_raw(&record),
+                    None => {
+                        let parts = [
+                            &region.country,
+                            &region.province,
+                            &region.city,
+                            &region.district,
+                        ]
+                        .into_iter()
+                        .flatten()
+                        .map(|x| x.as_str())
+                        .collect::<Vec<_>>();
+                        if parts.is_empty() {
+                            region.region.clone()
+                        } else {
+                            parts.join("–")
+                        }
+                    }
+                };
+                vec![country, self.area.render_raw(&record)]
+            }
+        }
+    }
+}
+
+// 纯真的原始字段, 国家按`–`拆分为国家、省份、城市及区县, 旧版数据整体作为国家
+fn qqwry_fields(r: &IpGeoInfo) -> IPRegion {
+    let raw = |s: &str| Some(s.to_string()).filter(|x| !x.is_empty());
+    let mut parts = r.country.split('–').map(raw);
+    let mut part = || parts.next().flatten();
+    let (country, province, city, district) = if r.country.contains('–') {
+        (part(), part(), part(), part())
+    } else {
+        (raw(&r.country), None, None, None)
+    };
+    IPRegion {
+        country,
+        province,
+        city,
+        district,
+        range: Some(IpRange::new(r.start.into(), r.end.into())),
+        ..IPRegion::new(r.start.to_string(), r.country.clone(), raw(&r.area))
+    }
+}
+
+// xdb地区按位置拆分, `0`表示缺失, 与地区重复的ISP同样保留
+fn xdb_fields(start: IpAddr, end: IpAddr, region: &str, version: u16) -> IPRegion {
+    let fields = region.split('|').collect::<Vec<&str>>();
+    let field = |i: usize| {
+        fields
+            .get(i)
+            .filter(|x| !x.is_empty() && **x != "0")
+            .map(|x| x.to_string())
+    };
+    let (province, city, isp) = if version <= 2 { (2, 3, 4) } else { (1, 2, 3) };
+    IPRegion {
+        country: field(0),
+        province: field(province),
+        city: field(city),
+        range: Some(IpRange::new(start, end)),
+        ..IPRegion::new(start.to_string(), region.to_string(), field(isp))
+    }
+}
+
+/// 转换结果的统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConvertStats {
+    /// 读取的IP段
+    pub read: usize,
+    /// 与前一个IP段字段相同而合并的IP段, 不包括`rejoined`
+    pub merged: usize,
+    /// 输入xdb中按前两个字节拆分的IP段重新合并的数量
+    pub rejoined: usize,
+    /// xdb按前两个字节拆分时新增的IP段
+    pub split: usize,
+    /// 写入的IP段
+    pub written: usize,
+}
+
+/// 在纯真、xdb及CSV之间转换, 字段按`mapping`映射
+///
+/// 映射后字段相同的相邻IP段会被合并
+pub fn convert<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: ConvertFormat,
+    to: ConvertFormat,
+    input: P,
+    output: Q,
+    mapping: &FieldMapping,
+) -> Result<ConvertStats, anyhow::Error> {
+    let input = PathBuf::from(replace_home(&input.as_ref().to_string_lossy()));
+    let mut stats = ConvertStats::default();
+    // 起止IP, 映射后的字段, xdb中的原始地区
+    let mut ranges: Vec<(IpAddr, IpAddr, Vec<String>, Option<String>)> = Vec::new();
+    let mut push = |start: IpAddr, end: IpAddr, region: IPRegion| {
+        stats.read += 1;
+        let fields = mapping.map(from, to, &region);
+        let raw = (from == ConvertFormat::Xdb).then_some(region.region);
+        ranges.push((start, end, fields, raw));
+    };
+    match from {
+        ConvertFormat::Qqwry => {
+            let data = open_qqwry(&input)?;
+            for r in data.records() {
+                let r = r.map_err(|e| LookupError::DatabaseCorrupt(e.to_string()))?;
+                push(r.start.into(), r.end.into(), qqwry_fields(&r));
+            }
+        }
+        ConvertFormat::Xdb => {
+            let searcher = open_xdb(&input)?;
+            let version = searcher.version();
+            for r in searcher.segments() {
+                let r = r.map_err(|e| LookupError::DatabaseCorrupt(e.to_string()))?;
+                push(
+                    r.start_ip,
+                    r.end_ip,
+                    xdb_fields(r.start_ip, r.end_ip, &r.region, version),
+                );
+            }
+        }
+        ConvertFormat::Csv => {
+            let file =
+                File::open(&input).map_err(|e| anyhow!("打开{}失败: {}", input.display(), e))?;
+            for row in csv_rows(io::BufReader::new(file)) {
+                let (_, start, end, region) = row?;
+                let version = mapping
+                    .csv_version
+                    .unwrap_or(if start.is_ipv4() { 2 } else { 3 });
+                push(start, end, xdb_fields(start, end, &region, version));
+            }
+        }
+    }
+
+    ranges.sort_by_key(|x| x.0);
+    let mut merged: Vec<(IpAddr, IpAddr, Vec<String>)> = Vec::with_capacity(ranges.len());
+    // 上一个IP段在xdb中的原始地区
+    let mut last_raw = None;
+    for (start, end, fields, raw) in ranges {
+        match merged.last_mut() {
+            Some(last) if next_ip(last.1) == Some(start) && last.2 == fields => {
+                last.1 = end;
+                // 原始地区相同且从拆分的边界开始, 为同一条记录
+                if raw.is_some() && raw == last_raw && is_split_start(start) {
+                    stats.rejoined += 1;
+                } else {
+                    stats.merged += 1;
+                }
+            }
+            _ => merged.push((start, end, fields)),
+        }
+        last_raw = raw;
+    }
+    stats.written = merged.len();
+
+    match to {
+        ConvertFormat::Qqwry => {
+            let mut writer = QQWryWriter::new();
+            for (start, end, fields) in &merged {
+                let (IpAddr::V4(start), IpAddr::V4(end)) = (start, end) else {
+                    return Err(anyhow!("纯真数据库只支持IPv4: {}-{}", start, end));
+                };
+                writer
+                    .add(*start, *end, &fields[0], &fields[1])
+                    .map_err(|e| anyhow!("生成失败: {}", e))?;
+            }
+            writer
+                .write(output)
+                .map_err(|e| anyhow!("生成失败: {}", e))?;
+        }
+        ConvertFormat::Xdb => {
+            let mut maker = new_maker(merged.first().ok_or(anyhow!("没有IP段"))?.0);
+            for (start, end, fields) in &merged {
+                maker.add(*start, *end, &fields[0])?;
+            }
+            maker
+                .write(output)
+                .map_err(|e| anyhow!("生成失败: {}", e))?;
+            stats.split = maker.segment_count() - maker.len();
+        }
+        ConvertFormat::Csv => {
+            let output = output.as_ref();
+            let file =
+                File::create(output).map_err(|e| anyhow!("创建{}失败: {}", output.display(), e))?;
+            let mut writer = RecordWriter {
+                out: io::BufWriter::new(file),
+                format: ExportFormat::Csv,
+                fields: Vec::new(),
+                count: 0,
+            };
+            writer.header(&["region"])?;
+            for (start, end, fields) in &merged {
+                writer.write(*start, *end, &[&fields[0]])?;
+            }
+            writer.out.flush()?;
+        }
+    }
+    Ok(stats)
+}
+
+// xdb按IP前两个字节拆分IP段, 拆分出的IP段从`x.y.0.0`开始
+fn is_split_start(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) & 0xffff == 0,
+        IpAddr::V6(ip) => u128::from(ip) << 16 == 0,
+    }
+}
+
+// 下一个IP, 已是最大值时返回None
+fn next_ip(ip: IpAddr) -> Option<IpAddr> {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip)
+            .checked_add(1)
+            .map(|x| Ipv4Addr::from(x).into()),
+        IpAddr::V6(ip) => u128::from(ip)
+            .checked_add(1)
+            .map(|x| Ipv6Addr::from(x).into()),
+    }
+}
+
+fn now() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs() as u32)
+}
+
+fn open_qqwry(path: &Path) -> Result<QQWryData, LookupError> {
+    QQWryData::new(path).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => LookupError::DatabaseCorrupt(e.to_string()),
+        _ => LookupError::DatabaseMissing(path.to_path_buf()),
+    })
+}
+
+fn open_ipv6wry(path: &Path) -> Result<Ipv6WryData, LookupError> {
+    Ipv6WryData::new(path).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => LookupError::DatabaseCorrupt(e.to_string()),
+        _ => LookupError::DatabaseMissing(path.to_path_buf()),
+    })
+}
+
+fn open_xdb(path: &Path) -> Result<XdbSearcher, LookupError> {
+    XdbSearcher::new(path).map_err(|e| match e {
+        XdbError::Io(_) => LookupError::DatabaseMissing(path.to_path_buf()),
+        e => LookupError::DatabaseCorrupt(e.to_string()),
+    })
+}
+
+// 打开xdb, IP类型与`kind`不一致时返回`DatabaseCorrupt`
+fn open_xdb_kind(kind: DatabaseKind, path: &Path) -> Result<XdbSearcher, LookupError> {
+    let searcher = open_xdb(path)?;
+    let expected = match kind {
+        DatabaseKind::Ip2regionV6 => IpVersion::V6,
+        _ => IpVersion::V4,
+    };
+    if searcher.ip_version() != expected {
+        return Err(LookupError::DatabaseCorrupt(format!(
+            "{}是{}数据库, 应为{}",
+            path.display(),
+            searcher.ip_version(),
+            expected
+        )));
+    }
+    Ok(searcher)
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect())
+}
+
+fn non_blank(s: &str) -> Option<String> {
+    let s = s.trim();
+    (!s.is_empty()).then(|| s.to_string())
+}
+
+// 从版本信息中提取日期, 支持`2024年10月16日`及`20241016`
+fn parse_date(s: &str) -> Option<String> {
+    static DATE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(\d{4})年(\d{1,2})月(\d{1,2})日|(\d{4})(\d{2})(\d{2})").unwrap()
+    });
+    let caps = DATE.captures(s)?;
+    let part = |a: usize, b: usize| caps.get(a).or(caps.get(b)).map(|x| x.as_str());
+    let year = part(1, 4)?;
+    let month: u32 = part(2, 5)?.parse().ok()?;
+    let day: u32 = part(3, 6)?.parse().ok()?;
+    Some(format!("{}-{:02}-{:02}", year, month, day))
+}
+
+// Unix时间戳转为UTC日期, 见 http://howardhinnant.github.io/date_algorithms.html
+fn format_timestamp(ts: u64) -> String {
+    let days = (ts / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("纯真网络2024年9月4日IP数据").as_deref(),
+            Some("2024-09-04")
+        );
+        assert_eq!(
+            parse_date("ZX公网IPv6库 20210510").as_deref(),
+            Some("2021-05-10")
+        );
+        assert_eq!(parse_date("IANA保留地址"), None);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29");
+    }
+
+    #[test]
+    fn test_record_writer() {
+        let start: IpAddr = "1.0.0.0".parse().unwrap();
+        let end: IpAddr = "1.0.0.255".parse().unwrap();
+        for (format, expected) in [
+            (
+                ExportFormat::Csv,
+                "start_ip,end_ip,country,area\n1.0.0.0,1.0.0.255,\"中国,广东\",电信\n",
+            ),
+            (
+                ExportFormat::Jsonl,
+                "{\"start_ip\":\"1.0.0.0\",\"end_ip\":\"1.0.0.255\",\"country\":\"中国,广东\",\"area\":\"电信\"}\n",
+            ),
+        ] {
+            let mut writer = RecordWriter {
+                out: Vec::new(),
+                format,
+                fields: Vec::new(),
+                count: 0,
+            };
+            writer.header(&["country", "area"]).unwrap();
+            writer.write(start, end, &["中国,广东", "电信"]).unwrap();
+            assert_eq!(writer.count, 1);
+            assert_eq!(String::from_utf8(writer.out).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_build_xdb() {
+        let dir = TempDir::new("build-xdb");
+        let path = dir.join("custom.xdb");
+        let csv = "start_ip,end_ip,region\n# 内网\n10.0.0.0,10.255.255.255,内网\n\n1.0.0.0,1.0.0.255,\"中国,\"\"广东\"\"\"\n";
+        assert_eq!(build_xdb(csv.as_bytes(), &path).unwrap(), 2);
+        let searcher = XdbSearcher::new(&path).unwrap();
+        assert_eq!(searcher.search("10.1.2.3").unwrap(), "内网");
+        assert_eq!(searcher.search("1.0.0.1").unwrap(), "中国,\"广东\"");
+        assert!(matches!(
+            searcher.search("8.8.8.8"),
+            Err(XdbError::NotMatched)
+        ));
+        // 导出后重新生成, 内容不变
+        let mut exported = Vec::new();
+        export(
+            DatabaseKind::Ip2region,
+            &path,
+            ExportFormat::Csv,
+            &mut exported,
+        )
+        .unwrap();
+        let rebuilt = dir.join("rebuilt.xdb");
+        build_xdb(exported.as_slice(), &rebuilt).unwrap();
+        let a = XdbSearcher::new(&rebuilt).unwrap();
+        assert_eq!(
+            a.segments().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            searcher.segments().map(|x| x.unwrap()).collect::<Vec<_>>()
+        );
+        // xdb只包含文件格式版本, IP类型需与数据库类型一致
+        let meta = metadata(DatabaseKind::Ip2region, &path).unwrap();
+        assert_eq!((meta.version, meta.format_version), (None, Some(2)));
+        assert_eq!(meta.records, searcher.header().count());
+        assert!(matches!(
+            metadata(DatabaseKind::Ip2regionV6, &path),
+            Err(LookupError::DatabaseCorrupt(_))
+        ));
+        assert!(export(
+            DatabaseKind::Ip2regionV6,
+            &path,
+            ExportFormat::Csv,
+            Vec::new()
+        )
+        .is_err());
+        for csv in [
+            "1.0.0.0,1.0.0.255",
+            "1.0.0.0,x,A",
+            "1.0.0.0,1.0.0.9,A\n::1,::2,B",
+            "",
+        ] {
+            assert!(build_xdb(csv.as_bytes(), dir.join("bad.xdb")).is_err());
+        }
+    }
+
+    #[test]
+    fn test_qqwry() {
+        let dir = TempDir::new("db-qqwry");
+        let path = dir.join("qqwry.dat");
+        let mut writer = crate::qqwry::QQWryWriter::new();
+        for (start, end, country, area) in [
+            ("0.0.0.0", "1.0.0.255", "中国–广东–深圳", "电信"),
+            (
+                "1.0.1.0",
+                "255.255.255.255",
+                "IANA保留地址",
+                "纯真网络2024年10月16日IP数据",
+            ),
+        ] {
+            writer
+                .add(start.parse().unwrap(), end.parse().unwrap(), country, area)
+                .unwrap();
+        }
+        writer.write(&path).unwrap();
+        let meta = metadata(DatabaseKind::Qqwry, &path).unwrap();
+        assert_eq!(meta.records, 2);
+        assert_eq!(meta.size, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(
+            meta.version.as_deref(),
+            Some("纯真网络2024年10月16日IP数据")
+        );
+        assert_eq!(meta.date.as_deref(), Some("2024-10-16"));
+        assert_eq!(meta.sha256.len(), 64);
+        let mut out = Vec::new();
+        assert_eq!(
+            export(DatabaseKind::Qqwry, &path, ExportFormat::Csv, &mut out).unwrap(),
+            2
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap().lines().nth(1),
+            Some("0.0.0.0,1.0.0.255,中国–广东–深圳,电信")
+        );
+    }
+
+    #[test]
+    fn test_convert() {
+        let dir = TempDir::new("db-convert");
+        let path = dir.join("qqwry.dat");
+        let mut writer = QQWryWriter::new();
+        for (start, end, country, area) in [
+            ("0.0.0.0", "0.255.255.255", "IANA保留地址", ""),
+            ("1.0.0.0", "1.0.0.255", "中国–广东–深圳", "电信"),
+            ("1.0.1.0", "1.0.1.255", "中国–广东–深圳", "电信"),
+            ("1.0.2.0", "1.0.2.255", "局域网", "局域网"),
+            ("1.0.3.0", "1.0.3.255", "局域网", " CZ88.NET"),
+            ("1.0.4.0", "1.1.0.255", "美国", ""),
+            (
+                "1.1.1.0",
+                "255.255.255.255",
+                "IANA保留地址",
+                "纯真网络2024年10月16日IP数据",
+            ),
+        ] {
+            writer
+                .add(start.parse().unwrap(), end.parse().unwrap(), country, area)
+                .unwrap();
+        }
+        writer.write(&path).unwrap();
+
+        // 字段相同的两段合并, 跨越前两个字节的IP段被拆分
+        let xdb = dir.join("qqwry.xdb");
+        let stats = convert(
+            ConvertFormat::Qqwry,
+            ConvertFormat::Xdb,
+            &path,
+            &xdb,
+            &FieldMapping::new(),
+        )
+        .unwrap();
+        assert_eq!((stats.read, stats.merged, stats.written), (7, 1, 6));
+        let searcher = XdbSearcher::new(&xdb).unwrap();
+        assert_eq!(stats.split, searcher.header().count() - stats.written);
+        assert!(stats.split > 0);
+        assert_eq!(searcher.search("0.1.2.3").unwrap(), "IANA保留地址|0|0|0|0");
+        assert_eq!(searcher.search("1.0.1.1").unwrap(), "中国|0|广东|深圳|电信");
+        // 与地区相同的ISP及原始字段不做清理
+        assert_eq!(searcher.search("1.0.2.1").unwrap(), "局域网|0|0|0|局域网");
+        assert_eq!(
+            searcher.search("1.0.3.1").unwrap(),
+            "局域网|0|0|0| CZ88.NET"
+        );
+        assert_eq!(searcher.search("1.1.0.1").unwrap(), "美国|0|0|0|0");
+
+        // 使用默认映射转回纯真, 除合并的IP段外与原数据一致
+        let dat = dir.join("roundtrip.dat");
+        convert(
+            ConvertFormat::Xdb,
+            ConvertFormat::Qqwry,
+            &xdb,
+            &dat,
+            &FieldMapping::new(),
+        )
+        .unwrap();
+        let records = |p: &Path| {
+            QQWryData::new(p)
+                .unwrap()
+                .records()
+                .map(|r| {
+                    let r = r.unwrap();
+                    (r.start, r.end, r.country, r.area)
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut expected = records(&path);
+        expected[1].1 = expected.remove(2).1;
+        assert_eq!(records(&dat), expected);
+
+        // xdb转为xdb及CSV时地区保持原样
+        let passthrough = dir.join("passthrough.csv");
+        std::fs::write(
+            &passthrough,
+            "0.0.0.0,255.255.255.255,0|0|0|内网IP|内网IP\n",
+        )
+        .unwrap();
+        let out = dir.join("passthrough.xdb");
+        convert(
+            ConvertFormat::Csv,
+            ConvertFormat::Xdb,
+            &passthrough,
+            &out,
+            &FieldMapping::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            XdbSearcher::new(&out).unwrap().search("10.0.0.1").unwrap(),
+            "0|0|0|内网IP|内网IP"
+        );
+
+        // 再转回纯真, 使用自定义的地区
+        let dat = dir.join("xdb.dat");
+        let mapping = FieldMapping::new().with_area("{isp:-未知}".parse().unwrap());
+        let stats = convert(
+            ConvertFormat::Xdb,
+            ConvertFormat::Qqwry,
+            &xdb,
+            &dat,
+            &mapping,
+        )
+        .unwrap();
+        // xdb中拆分的IP段重新合并, 不计入合并的数量
+        assert_eq!(stats.read, searcher.header().count());
+        assert_eq!((stats.written, stats.split, stats.merged), (6, 0, 0));
+        assert_eq!(stats.rejoined, stats.read - stats.written);
+        let data = QQWryData::new(&dat).unwrap();
+        let r = data.query("1.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(
+            (r.country.as_str(), r.area.as_str()),
+            ("中国–广东–深圳", "电信")
+        );
+        let r = data.query("1.0.5.1".parse().unwrap()).unwrap();
+        assert_eq!((r.country.as_str(), r.area.as_str()), ("美国", "未知"));
+
+        let csv = dir.join("xdb.csv");
+        let mapping = FieldMapping::new().with_region("{country}/{isp}".parse().unwrap());
+        convert(ConvertFormat::Xdb, ConvertFormat::Csv, &xdb, &csv, &mapping).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&csv).unwrap().lines().nth(2),
+            Some("1.0.0.0,1.0.1.255,中国/电信")
+        );
+
+        // CSV中的地区默认按IP类型确定格式版本, 也可指定
+        let v3 = dir.join("v3.csv");
+        let out = dir.join("v3-out.csv");
+        let mapping = FieldMapping::new().with_region("{province}/{isp}".parse().unwrap());
+        for (content, mapping) in [
+            ("::,::ffff,中国|广东省|深圳市|电信\n", mapping.clone()),
+            (
+                "1.0.0.0,1.0.0.255,中国|广东省|深圳市|电信\n",
+                mapping.with_csv_version(3),
+            ),
+        ] {
+            std::fs::write(&v3, content).unwrap();
+            convert(ConvertFormat::Csv, ConvertFormat::Csv, &v3, &out, &mapping).unwrap();
+            assert!(std::fs::read_to_string(&out)
+                .unwrap()
+                .ends_with(",广东省/电信\n"));
+        }
+
+        // IPv6不能转为纯真
+        std::fs::write(&csv, "::,::ffff,A\n").unwrap();
+        assert!(convert(
+            ConvertFormat::Csv,
+            ConvertFormat::Qqwry,
+            &csv,
+            dir.join("v6.dat"),
+            &FieldMapping::new()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_missing() {
+        assert!(matches!(
+            metadata(DatabaseKind::Qqwry, "/nonexistent/qqwry.dat"),
+            Err(LookupError::DatabaseMissing(_))
+        ));
+        let err = export(
+            DatabaseKind::Ip2region,
+            "/nonexistent/ip2region.xdb",
+            ExportFormat::Csv,
+            Vec::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LookupError>(),
+            Some(LookupError::DatabaseMissing(_))
+        ));
+    }
+}
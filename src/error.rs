@@ -0,0 +1,101 @@
+//! 查询错误
+
+use std::{fmt, path::PathBuf};
+
+#[derive(Debug)]
+pub enum LookupError {
+    /// 未查询到结果
+    NotFound,
+    /// IP格式错误
+    InvalidIp(String),
+    /// 渠道不支持该查询, 如zxinc不支持IPv4
+    Unsupported(String),
+    /// 网络请求失败
+    Network(String),
+    /// 在线渠道返回了错误或无法解析的结果
+    Service(String),
+    /// 离线数据库不存在或无法读取
+    DatabaseMissing(PathBuf),
+    /// 离线数据库已损坏
+    DatabaseCorrupt(String),
+}
+
+impl LookupError {
+    /// 命令行的退出码, 1和2分别保留给一般错误及参数错误
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::NotFound => 3,
+            Self::InvalidIp(_) => 4,
+            Self::Unsupported(_) => 5,
+            Self::Network(_) => 6,
+            Self::Service(_) => 7,
+            Self::DatabaseMissing(_) => 8,
+            Self::DatabaseCorrupt(_) => 9,
+        }
+    }
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "未查询到结果！"),
+            Self::InvalidIp(ip) => write!(f, "IP格式错误: {}", ip),
+            Self::Unsupported(msg) => write!(f, "{}", msg),
+            Self::Network(msg) => write!(f, "网络错误: {}", msg),
+            Self::Service(msg) => write!(f, "查询出错: {}", msg),
+            Self::DatabaseMissing(path) => {
+                write!(f, "数据库不存在或无法读取: {}", path.display())
+            }
+            Self::DatabaseCorrupt(msg) => write!(f, "数据库已损坏: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+impl From<reqwest::Error> for LookupError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            return Self::Service(e.to_string());
+        }
+        Self::Network(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        let errors = [
+            LookupError::NotFound,
+            LookupError::InvalidIp("x".to_string()),
+            LookupError::Unsupported("x".to_string()),
+            LookupError::Network("x".to_string()),
+            LookupError::Service("x".to_string()),
+            LookupError::DatabaseMissing(PathBuf::from("x")),
+            LookupError::DatabaseCorrupt("x".to_string()),
+        ];
+        assert_eq!(
+            errors.iter().map(|e| e.exit_code()).collect::<Vec<_>>(),
+            [3, 4, 5, 6, 7, 8, 9]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_from_reqwest() {
+        // 连接失败为网络错误
+        let e = reqwest::get("http://127.0.0.1:1/").await.unwrap_err();
+        assert!(matches!(LookupError::from(e), LookupError::Network(_)));
+        // 无法解析的响应为查询出错
+        let url = crate::util::serve_once("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nnull").await;
+        let e = reqwest::get(url)
+            .await
+            .unwrap()
+            .json::<Vec<u8>>()
+            .await
+            .unwrap_err();
+        assert!(matches!(LookupError::from(e), LookupError::Service(_)));
+    }
+}
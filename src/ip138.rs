@@ -1,13 +1,16 @@
 //! ip138
 
-use crate::{util::wait_blink, IPRegion};
-
-use anyhow::anyhow;
+use crate::{
+    error::LookupError,
+    provider::{Capabilities, LookupFuture, Provider},
+    util::non_empty,
+    IPRegion,
+};
 
 use regex::Regex;
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde_json::Value;
-use std::{collections::HashMap, env, path::PathBuf, sync::LazyLock};
+use std::{collections::HashMap, env, net::IpAddr, path::PathBuf, sync::LazyLock};
 use tokio::fs;
 
 const DEFAULT_CONFIG: &str = r#"
@@ -67,25 +70,16 @@ async fn read_config() -> String {
         .unwrap_or(String::new())
 }
 
-async fn get_html(
-    url: &str,
-    headers: &'static HashMap<&str, &str>,
-) -> Result<String, anyhow::Error> {
+async fn get_html(url: &str, headers: &'static HashMap<&str, &str>) -> Result<String, LookupError> {
     let custom = reqwest::redirect::Policy::custom(|attempt| attempt.stop());
     let client = reqwest::Client::builder().redirect(custom);
     let mut header = HeaderMap::new();
     for (k, v) in headers {
         header.insert(*k, HeaderValue::from_str(v).unwrap());
     }
-    let resp = client
-        .build()?
-        .get(url)
-        .headers(header)
-        .send()
-        .await
-        .unwrap();
+    let resp = client.build()?.get(url).headers(header).send().await?;
     if resp.status() != 200 {
-        return Ok(String::new());
+        return Err(LookupError::Service(format!("HTTP {}", resp.status())));
     }
     Ok(resp.text_with_charset("gb2312").await?)
 }
@@ -94,8 +88,28 @@ fn process_field(s: &str) -> String {
     s.replace(['"', ' '], "")
 }
 
-pub async fn query_ip138(ip: &str) -> Result<IPRegion, anyhow::Error> {
-    let wait = wait_blink("查询中，请稍候🔎...", 3);
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IP138Provider;
+
+impl Provider for IP138Provider {
+    fn name(&self) -> &str {
+        "IP138.COM"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ipv4: true,
+            ipv6: false,
+            offline: false,
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> LookupFuture<'_> {
+        Box::pin(query_ip138(ip))
+    }
+}
+
+pub async fn query_ip138(ip: IpAddr) -> Result<IPRegion, LookupError> {
     let html_s = get_html(
         format!("https://www.ip138.com/iplookup.asp?ip={}&action=2", ip).as_str(),
         &HEADERS,
@@ -106,18 +120,69 @@ pub async fn query_ip138(ip: &str) -> Result<IPRegion, anyhow::Error> {
         Some(e) => e.get(0).map_or("", |m| m.as_str()),
         None => "",
     };
-    wait.sender.send(true).unwrap();
-    wait.handle.await?;
     if res.is_empty() {
-        return Err(anyhow!("未查询到结果！"));
+        return Err(LookupError::NotFound);
     }
 
     let res = &res.to_string()[16..];
     let res = res.replace(';', "");
-    let v: Value = serde_json::from_str(&res).unwrap();
-    Ok(IPRegion::new(
-        ip.to_string(),
-        process_field(v["ASN归属地"].as_str().unwrap_or("")).to_string(),
-        None,
-    ))
+    let v: Value = serde_json::from_str(&res).map_err(|e| LookupError::Service(e.to_string()))?;
+    Ok(parse_result(&ip.to_string(), &v))
+}
+
+// `ip_c_list`中包含结构化的国家(ct)、省份(prov)、城市(city)、区县(area)及运营商(yunyin)
+fn parse_result(ip: &str, v: &Value) -> IPRegion {
+    let region = process_field(v["ASN归属地"].as_str().unwrap_or(""));
+    let item = &v["ip_c_list"][0];
+    let field = |k: &str| item[k].as_str().and_then(non_empty);
+    IPRegion {
+        country: field("ct"),
+        province: field("prov"),
+        city: field("city"),
+        district: field("area"),
+        isp: field("yunyin"),
+        ..IPRegion::new(ip.to_string(), region, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::LazyLock};
+
+    use super::{get_html, parse_result};
+    use crate::{error::LookupError, util::serve_once};
+
+    #[test]
+    fn test_parse_result() {
+        let v = serde_json::from_str(
+            r#"{"ASN归属地":"中国广东深圳 电信","ip_c_list":[{"ct":"中国","prov":"广东省","city":"深圳市","area":"","yunyin":"电信"}]}"#,
+        )
+        .unwrap();
+        let r = parse_result("1.2.3.4", &v);
+        assert_eq!(r.region, "中国广东深圳电信");
+        assert_eq!(r.country.as_deref(), Some("中国"));
+        assert_eq!(r.province.as_deref(), Some("广东省"));
+        assert_eq!(r.city.as_deref(), Some("深圳市"));
+        assert_eq!(r.district, None);
+        assert_eq!(r.isp.as_deref(), Some("电信"));
+        let r = parse_result(
+            "1.2.3.4",
+            &serde_json::from_str(r#"{"ASN归属地":"美国"}"#).unwrap(),
+        );
+        assert_eq!(r.region, "美国");
+        assert_eq!(r.country, None);
+    }
+
+    #[tokio::test]
+    async fn test_get_html() {
+        static EMPTY: LazyLock<HashMap<&str, &str>> = LazyLock::new(HashMap::new);
+        // 被拦截或重定向时为查询出错, 而不是未查询到结果
+        let url =
+            serve_once("HTTP/1.1 302 Found\r\nLocation: /\r\nContent-Length: 0\r\n\r\n").await;
+        let err = get_html(&url, &EMPTY).await.unwrap_err();
+        assert!(matches!(err, LookupError::Service(_)));
+        assert_eq!(err.exit_code(), 7);
+        let url = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        assert_eq!(get_html(&url, &EMPTY).await.unwrap(), "ok");
+    }
 }
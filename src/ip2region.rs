@@ -1,12 +1,23 @@
 //! https://github.com/lionsoul2014/ip2region
 
-use std::{collections::HashMap, path::PathBuf, sync::LazyLock};
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, LazyLock},
+};
+
+use futures::future::BoxFuture;
+use tokio::sync::RwLock;
 
-use crate::xdb::{search_by_ip, searcher_init};
-use anyhow::anyhow;
+pub use crate::xdb::{
+    CachePolicy, Header, IpVersion, Segment, Segments, XdbError, XdbMaker, XdbSearcher,
+};
 
 use crate::{
-    util::{download_file, replace_home, wait_blink},
+    error::LookupError,
+    ip::IpRange,
+    provider::{Capabilities, LookupFuture, Provider},
+    util::{download_database, non_empty, replace_home},
     IPRegion,
 };
 
@@ -18,34 +29,224 @@ pub static XDB_FILEPATH: LazyLock<PathBuf> = LazyLock::new(|| {
     PathBuf::from(p)
 });
 
-pub async fn query_ip2region(ip: &str, xdb_path: Option<&str>) -> Result<IPRegion, anyhow::Error> {
-    let wait = wait_blink("查询中，请稍候🔎...", 3);
-    let xdb_path = replace_home(xdb_path.unwrap_or(XDB_FILEPATH.to_str().unwrap()));
-    if !PathBuf::from(xdb_path.clone()).exists() {
-        download_file(XDB_URL, &XDB_FILEPATH).await?;
-        searcher_init(Some(XDB_FILEPATH.to_str().unwrap().to_string()));
-    } else {
-        searcher_init(Some(xdb_path));
-    }
-    wait.sender.send(true).unwrap();
-    wait.handle.await?;
-    if ip.contains(":") {
-        return Err(anyhow!("暂不支持IPv6"));
-    }
-    match search_by_ip(ip) {
-        Ok(r) => {
-            let r = r.split('|').filter(|x| *x != "0").collect::<Vec<&str>>();
-            let mut item_count = HashMap::new();
-            let mut res = Vec::new();
-            for x in r {
-                if item_count.contains_key(x) {
-                    continue;
+pub const XDB_V6_URL: &str =
+    "https://cdn.jsdelivr.net/gh/lionsoul2014/ip2region/data/ip2region_v6.xdb";
+
+pub static XDB_V6_FILEPATH: LazyLock<PathBuf> = LazyLock::new(|| {
+    let p = replace_home("~/.cache/ipr/ip2region_v6.xdb");
+    PathBuf::from(p)
+});
+
+/// ip2region离线数据库, IPv4及IPv6分别使用各自的xdb文件
+///
+/// 与纯真相同, 数据库在第一次查询时加载并复用
+#[derive(Debug, Clone, Default)]
+pub struct IP2RegionProvider {
+    xdb_path: Option<String>,
+    ipv6_xdb_path: Option<String>,
+    cache_policy: CachePolicy,
+    searcher: Arc<RwLock<Option<Arc<XdbSearcher>>>>,
+    ipv6_searcher: Arc<RwLock<Option<Arc<XdbSearcher>>>>,
+}
+
+impl IP2RegionProvider {
+    pub fn new(xdb_path: Option<String>) -> Self {
+        Self {
+            xdb_path,
+            ..Default::default()
+        }
+    }
+
+    /// 使用已打开的xdb, 按文件头中的IP类型用于IPv4或IPv6查询,
+    /// `reload`时从`xdb_path`(默认 ~/.cache/ipr/ip2region.xdb或ip2region_v6.xdb)重新加载
+    pub fn with_searcher(searcher: Arc<XdbSearcher>, xdb_path: Option<String>) -> Self {
+        let mut provider = Self {
+            cache_policy: searcher.policy(),
+            ..Default::default()
+        };
+        match searcher.ip_version() {
+            IpVersion::V4 => {
+                provider.xdb_path = xdb_path;
+                provider.searcher = Arc::new(RwLock::new(Some(searcher)));
+            }
+            IpVersion::V6 => {
+                provider.ipv6_xdb_path = xdb_path;
+                provider.ipv6_searcher = Arc::new(RwLock::new(Some(searcher)));
+            }
+        }
+        provider
+    }
+
+    /// IPv6 xdb文件所在地址, 默认 ~/.cache/ipr/ip2region_v6.xdb
+    pub fn with_ipv6_path(mut self, ipv6_xdb_path: Option<String>) -> Self {
+        self.ipv6_xdb_path = ipv6_xdb_path;
+        self
+    }
+
+    /// xdb的缓存策略, 默认缓存整个文件
+    pub fn with_cache_policy(mut self, cache_policy: CachePolicy) -> Self {
+        self.cache_policy = cache_policy;
+        self
+    }
+
+    /// 返回已加载的xdb, 未加载时先加载
+    pub async fn searcher(&self, ip_version: IpVersion) -> Result<Arc<XdbSearcher>, LookupError> {
+        let (slot, xdb_path) = self.slot(ip_version);
+        if let Some(searcher) = slot.read().await.as_ref() {
+            return Ok(searcher.clone());
+        }
+        let mut searcher = slot.write().await;
+        if let Some(searcher) = searcher.as_ref() {
+            return Ok(searcher.clone());
+        }
+        let loaded = Arc::new(load_xdb(xdb_path, ip_version, self.cache_policy).await?);
+        *searcher = Some(loaded.clone());
+        Ok(loaded)
+    }
+
+    fn slot(&self, ip_version: IpVersion) -> (&RwLock<Option<Arc<XdbSearcher>>>, Option<&str>) {
+        match ip_version {
+            IpVersion::V4 => (&self.searcher, self.xdb_path.as_deref()),
+            IpVersion::V6 => (&self.ipv6_searcher, self.ipv6_xdb_path.as_deref()),
+        }
+    }
+}
+
+impl Provider for IP2RegionProvider {
+    fn name(&self) -> &str {
+        "IP2REGION"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ipv4: true,
+            ipv6: true,
+            offline: true,
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> LookupFuture<'_> {
+        Box::pin(async move {
+            let searcher = self.searcher(ip_version(ip)).await?;
+            query_xdb(&searcher, ip)
+        })
+    }
+
+    fn reload(&self) -> BoxFuture<'_, Result<(), LookupError>> {
+        Box::pin(async move {
+            for ip_version in [IpVersion::V4, IpVersion::V6] {
+                let (slot, xdb_path) = self.slot(ip_version);
+                let mut searcher = slot.write().await;
+                // 未加载过的数据库在下次查询时再加载
+                if searcher.is_some() {
+                    *searcher = Some(Arc::new(
+                        load_xdb(xdb_path, ip_version, self.cache_policy).await?,
+                    ));
                 }
-                res.push(x);
-                item_count.insert(x, 1);
             }
-            Ok(IPRegion::new(ip.to_string(), res.join(""), None))
+            Ok(())
+        })
+    }
+}
+
+fn ip_version(ip: IpAddr) -> IpVersion {
+    match ip {
+        IpAddr::V4(_) => IpVersion::V4,
+        IpAddr::V6(_) => IpVersion::V6,
+    }
+}
+
+// 加载数据库, 默认路径的数据库不存在时自动下载
+async fn load_xdb(
+    xdb_path: Option<&str>,
+    ip_version: IpVersion,
+    cache_policy: CachePolicy,
+) -> Result<XdbSearcher, LookupError> {
+    let (default_path, url) = match ip_version {
+        IpVersion::V4 => (&*XDB_FILEPATH, XDB_URL),
+        IpVersion::V6 => (&*XDB_V6_FILEPATH, XDB_V6_URL),
+    };
+    let xdb_path = match xdb_path {
+        Some(p) => PathBuf::from(replace_home(p)),
+        None => default_path.clone(),
+    };
+    if !xdb_path.exists() && xdb_path == *default_path {
+        download_database(url, default_path).await?;
+    }
+    if !xdb_path.is_file() {
+        return Err(LookupError::DatabaseMissing(xdb_path));
+    }
+    XdbSearcher::with_policy(&xdb_path, cache_policy).map_err(|e| match e {
+        XdbError::Io(_) => LookupError::DatabaseMissing(xdb_path),
+        e => LookupError::DatabaseCorrupt(e.to_string()),
+    })
+}
+
+/// IPv6使用默认路径的ip2region_v6.xdb
+pub async fn query_ip2region(ip: IpAddr, xdb_path: Option<&str>) -> Result<IPRegion, LookupError> {
+    let xdb_path = xdb_path.filter(|_| ip.is_ipv4());
+    query_xdb(
+        &load_xdb(xdb_path, ip_version(ip), CachePolicy::default()).await?,
+        ip,
+    )
+}
+
+fn query_xdb(searcher: &XdbSearcher, ip: IpAddr) -> Result<IPRegion, LookupError> {
+    match searcher.search_ip(ip) {
+        Ok(r) => Ok(IPRegion {
+            range: Some(IpRange::new(r.start_ip, r.end_ip)),
+            ..parse_region(&ip.to_string(), &r.region, searcher.version())
+        }),
+        Err(XdbError::NotMatched) => Err(LookupError::NotFound),
+        Err(XdbError::InvalidIp(ip)) => Err(LookupError::InvalidIp(ip)),
+        Err(e @ XdbError::IpVersionMismatch(_)) => Err(LookupError::Unsupported(e.to_string())),
+        Err(e) => Err(LookupError::DatabaseCorrupt(e.to_string())),
+    }
+}
+
+// 旧版(version 2)数据格式为`国家|区域|省份|城市|ISP`,
+// 新版为`国家|省份|城市|ISP`, 之后可能还有其他字段, 缺失的字段为`0`
+fn parse_region(ip: &str, r: &str, version: u16) -> IPRegion {
+    let fields = r.split('|').collect::<Vec<&str>>();
+    let field = |i: usize| fields.get(i).and_then(|x| non_empty(x));
+    let (province, city, isp) = if version <= 2 { (2, 3, 4) } else { (1, 2, 3) };
+    let mut res: Vec<&str> = Vec::new();
+    for x in fields.iter().take(isp).filter(|x| **x != "0") {
+        if !res.contains(x) {
+            res.push(x);
         }
-        Err(e) => Err(anyhow!(e.to_string())),
+    }
+    // ISP与地区重复时(如`内网IP`)不再单独展示
+    let isp = field(isp).filter(|x| !res.contains(&x.as_str()));
+    IPRegion {
+        country: field(0),
+        province: field(province),
+        city: field(city),
+        ..IPRegion::new(ip.to_string(), res.join(""), isp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_region;
+
+    #[test]
+    fn test_parse_region() {
+        let r = parse_region("1.2.3.4", "中国|0|广东省|深圳市|电信", 2);
+        assert_eq!(r.region, "中国广东省深圳市");
+        assert_eq!(r.country.as_deref(), Some("中国"));
+        assert_eq!(r.province.as_deref(), Some("广东省"));
+        assert_eq!(r.city.as_deref(), Some("深圳市"));
+        assert_eq!(r.isp.as_deref(), Some("电信"));
+        let r = parse_region("10.0.0.1", "0|0|0|内网IP|内网IP", 2);
+        assert_eq!(r.region, "内网IP");
+        assert_eq!(r.country, None);
+        assert_eq!(r.city.as_deref(), Some("内网IP"));
+        assert_eq!(r.isp, None);
+        let r = parse_region("240e::1", "中国|广东省|深圳市|电信|CN", 3);
+        assert_eq!(r.region, "中国广东省深圳市");
+        assert_eq!(r.province.as_deref(), Some("广东省"));
+        assert_eq!(r.city.as_deref(), Some("深圳市"));
+        assert_eq!(r.isp.as_deref(), Some("电信"));
     }
 }
@@ -0,0 +1,356 @@
+//! ZX IPv6地址库`ipv6wry.db`
+//!
+//! 文件头: `IPDB`、版本(u16)、偏移量长度(u8)、IP长度(u8)、记录数(u64)及索引区位置(u64),
+//! 索引为`IP长度`字节的起始IP(取IPv6的高位)及`偏移量长度`字节的记录位置,
+//! 记录与纯真相同, 以0x01/0x02表示重定向, 字符串为UTF-8
+use std::io;
+use std::net::Ipv6Addr;
+use std::path::Path;
+
+use crate::qqwry_lib::CorruptDatabase;
+use crate::storage::Storage;
+
+const MAGIC: &[u8] = b"IPDB";
+const HEADER_SIZE: usize = 24;
+// 重定向的最大次数, 避免损坏的数据库形成环
+const MAX_REDIRECTS: usize = 8;
+
+#[derive(Debug)]
+pub struct Ipv6GeoInfo {
+    pub country: String,
+    pub area: String,
+    // 命中记录的IP段
+    pub start: Ipv6Addr,
+    pub end: Ipv6Addr,
+}
+
+pub struct Ipv6WryData {
+    cache: Storage,
+    offlen: usize,
+    iplen: usize,
+    count: usize,
+    index: usize,
+}
+
+impl std::fmt::Debug for Ipv6WryData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ipv6WryData")
+            .field("size", &self.cache.len())
+            .field("count", &self.count)
+            .finish()
+    }
+}
+
+impl Ipv6WryData {
+    /// 开启`mmap`特性时通过mmap加载, 否则读入内存, 文件头无效时返回`InvalidData`
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Ipv6WryData> {
+        Ok(Ipv6WryData::from_storage(Storage::open(path)?)?)
+    }
+
+    /// 读入内存
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Ipv6WryData> {
+        Ok(Ipv6WryData::from_storage(Storage::read(path)?)?)
+    }
+
+    /// 通过mmap加载, 多个进程共享同一份页缓存
+    #[cfg(feature = "mmap")]
+    pub fn mmap<P: AsRef<Path>>(path: P) -> io::Result<Ipv6WryData> {
+        Ok(Ipv6WryData::from_storage(Storage::mmap(path)?)?)
+    }
+
+    pub fn from_bytes(cache: Vec<u8>) -> Result<Ipv6WryData, CorruptDatabase> {
+        Ipv6WryData::from_storage(cache.into())
+    }
+
+    // 校验文件头及索引区范围
+    fn from_storage(cache: Storage) -> Result<Ipv6WryData, CorruptDatabase> {
+        let corrupt = |offset| CorruptDatabase { offset };
+        if cache.len() < HEADER_SIZE || &cache[..4] != MAGIC {
+            return Err(corrupt(0));
+        }
+        let offlen = cache[6] as usize;
+        let iplen = cache[7] as usize;
+        if !(1..=8).contains(&offlen) || !(1..=8).contains(&iplen) {
+            return Err(corrupt(6));
+        }
+        let mut data = Ipv6WryData {
+            cache,
+            offlen,
+            iplen,
+            count: 0,
+            index: 0,
+        };
+        data.count = data.read_uint(8, 8)? as usize;
+        data.index = data.read_uint(16, 8)? as usize;
+        let index_len = data.count.checked_mul(iplen + offlen).ok_or(corrupt(8))?;
+        if data.count == 0 {
+            return Err(corrupt(8));
+        }
+        data.bytes(data.index, index_len).map_err(|_| corrupt(16))?;
+        Ok(data)
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// 文件头中的格式版本
+    pub fn version(&self) -> u16 {
+        u16::from_le_bytes([self.cache[4], self.cache[5]])
+    }
+
+    /// 记录位置的字节数
+    pub fn offset_len(&self) -> usize {
+        self.offlen
+    }
+
+    /// 索引中IP的字节数
+    pub fn ip_len(&self) -> usize {
+        self.iplen
+    }
+
+    /// 索引区的位置
+    pub fn index_start(&self) -> usize {
+        self.index
+    }
+
+    pub fn query(&self, ip_addr: Ipv6Addr) -> Result<Ipv6GeoInfo, CorruptDatabase> {
+        // 索引只保存IP的高`iplen`字节
+        let shift = 128 - self.iplen as u32 * 8;
+        let key = u128::from(ip_addr) >> shift;
+        let (mut l, mut r) = (0, self.count);
+        while r - l > 1 {
+            let m = (l + r) / 2;
+            if key < self.index_key(m)? {
+                r = m;
+            } else {
+                l = m;
+            }
+        }
+        self.record(l)
+    }
+
+    /// 按IP顺序遍历所有记录, 遇到错误后停止
+    pub fn records(&self) -> Ipv6Records<'_> {
+        Ipv6Records { data: self, i: 0 }
+    }
+
+    // 读取第`l`条索引指向的记录, 结束IP为下一条索引的开始IP减1
+    fn record(&self, l: usize) -> Result<Ipv6GeoInfo, CorruptDatabase> {
+        let shift = 128 - self.iplen as u32 * 8;
+        let start = self.index_key(l)? << shift;
+        let end = match l + 1 < self.count {
+            true => (self.index_key(l + 1)? << shift).wrapping_sub(1),
+            false => u128::MAX,
+        };
+        let record_offset = self.read_uint(self.index_offset(l) + self.iplen, self.offlen)?;
+        let (country, area) = self.read_addr(record_offset as usize)?;
+        Ok(Ipv6GeoInfo {
+            country,
+            area,
+            start: Ipv6Addr::from(start),
+            end: Ipv6Addr::from(end),
+        })
+    }
+
+    #[inline]
+    fn index_offset(&self, i: usize) -> usize {
+        self.index + i * (self.iplen + self.offlen)
+    }
+
+    #[inline]
+    fn index_key(&self, i: usize) -> Result<u128, CorruptDatabase> {
+        self.read_uint(self.index_offset(i), self.iplen)
+            .map(|x| x as u128)
+    }
+
+    fn read_addr(&self, mut offset: usize) -> Result<(String, String), CorruptDatabase> {
+        for _ in 0..MAX_REDIRECTS {
+            let mode = self.read_u8(offset)?;
+            if mode == 0x01 {
+                offset = self.read_uint(offset + 1, self.offlen)? as usize;
+                continue;
+            }
+            let (country, len) = self.read_area(offset)?;
+            let area_offset = match mode {
+                0x02 => offset + 1 + self.offlen,
+                _ => offset + len + 1,
+            };
+            return Ok((country, self.read_area(area_offset)?.0));
+        }
+        Err(CorruptDatabase { offset })
+    }
+
+    // 返回字符串及其字节长度(不含结尾的0)
+    fn read_area(&self, mut offset: usize) -> Result<(String, usize), CorruptDatabase> {
+        for _ in 0..MAX_REDIRECTS {
+            match self.read_u8(offset)? {
+                0x01 | 0x02 => offset = self.read_uint(offset + 1, self.offlen)? as usize,
+                _ => return self.read_cstring(offset),
+            }
+        }
+        Err(CorruptDatabase { offset })
+    }
+
+    #[inline]
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], CorruptDatabase> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.cache.get(offset..end))
+            .ok_or(CorruptDatabase { offset })
+    }
+
+    #[inline]
+    fn read_u8(&self, offset: usize) -> Result<u8, CorruptDatabase> {
+        self.bytes(offset, 1).map(|x| x[0])
+    }
+
+    // 小端序, 最多8字节
+    fn read_uint(&self, offset: usize, len: usize) -> Result<u64, CorruptDatabase> {
+        let mut buf = [0u8; 8];
+        buf[..len].copy_from_slice(self.bytes(offset, len)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn read_cstring(&self, offset: usize) -> Result<(String, usize), CorruptDatabase> {
+        let corrupt = CorruptDatabase { offset };
+        let bytes = self.cache.get(offset..).ok_or(corrupt)?;
+        let len = bytes.iter().position(|x| *x == 0).ok_or(corrupt)?;
+        Ok((String::from_utf8_lossy(&bytes[..len]).to_string(), len))
+    }
+}
+
+/// 所有记录的迭代器, 见[`Ipv6WryData::records`]
+pub struct Ipv6Records<'a> {
+    data: &'a Ipv6WryData,
+    i: usize,
+}
+
+impl Iterator for Ipv6Records<'_> {
+    type Item = Result<Ipv6GeoInfo, CorruptDatabase>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.i >= self.data.count {
+            return None;
+        }
+        let res = self.data.record(self.i);
+        self.i = match res {
+            Ok(_) => self.i + 1,
+            Err(_) => usize::MAX,
+        };
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cstr(s: &str) -> Vec<u8> {
+        let mut x = s.as_bytes().to_vec();
+        x.push(0);
+        x
+    }
+
+    // 3条记录: 直接存储、国家重定向(0x02)及完全重定向(0x01)
+    fn sample() -> Vec<u8> {
+        let mut buf = vec![0u8; HEADER_SIZE];
+        buf[..4].copy_from_slice(MAGIC);
+        buf[4..6].copy_from_slice(&2u16.to_le_bytes());
+        buf[6] = 3;
+        buf[7] = 8;
+        let r1 = buf.len();
+        buf.extend(cstr("中国\t广东省\t深圳市"));
+        buf.extend(cstr("中国电信"));
+        let r2 = buf.len();
+        buf.push(0x02);
+        buf.extend(&(r1 as u32).to_le_bytes()[..3]);
+        buf.extend(cstr("中国联通"));
+        let r3 = buf.len();
+        buf.push(0x01);
+        buf.extend(&(r1 as u32).to_le_bytes()[..3]);
+        let index = buf.len();
+        for (start, offset) in [(0u64, r1), (0x240e_0000_0000_0000, r2), (0x2a00 << 48, r3)] {
+            buf.extend(start.to_le_bytes());
+            buf.extend(&(offset as u32).to_le_bytes()[..3]);
+        }
+        buf[8..16].copy_from_slice(&3u64.to_le_bytes());
+        buf[16..24].copy_from_slice(&(index as u64).to_le_bytes());
+        buf
+    }
+
+    fn query(data: &Ipv6WryData, ip: &str) -> Ipv6GeoInfo {
+        data.query(ip.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_query() {
+        let data = Ipv6WryData::from_bytes(sample()).unwrap();
+        assert_eq!(data.count(), 3);
+        let r = query(&data, "::1");
+        assert_eq!(
+            (r.country.as_str(), r.area.as_str()),
+            ("中国\t广东省\t深圳市", "中国电信")
+        );
+        let r = query(&data, "240e:3b7::1");
+        assert_eq!(
+            (r.country.as_str(), r.area.as_str()),
+            ("中国\t广东省\t深圳市", "中国联通")
+        );
+        assert_eq!(r.start, "240e::".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(
+            r.end,
+            "29ff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"
+                .parse::<Ipv6Addr>()
+                .unwrap()
+        );
+        let r = query(&data, "ffff::1");
+        assert_eq!(r.area, "中国电信");
+        assert_eq!(r.end, Ipv6Addr::from(u128::MAX));
+    }
+
+    #[test]
+    fn test_records() {
+        let data = Ipv6WryData::from_bytes(sample()).unwrap();
+        let records = data.records().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1].area, "中国联通");
+        assert_eq!(
+            records[0].end,
+            "240d:ffff:ffff:ffff:ffff:ffff:ffff:ffff"
+                .parse::<Ipv6Addr>()
+                .unwrap()
+        );
+        assert_eq!(records[2].start, "2a00::".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(records[2].end, Ipv6Addr::from(u128::MAX));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let sample = sample();
+        for len in 0..sample.len() {
+            if let Ok(data) = Ipv6WryData::from_bytes(sample[..len].to_vec()) {
+                data.query("240e::1".parse().unwrap()).ok();
+            }
+        }
+        assert!(Ipv6WryData::from_bytes(b"IPDX".repeat(8)).is_err());
+        // 重定向形成环
+        let mut buf = sample.clone();
+        buf[HEADER_SIZE] = 0x01;
+        buf[HEADER_SIZE + 1..HEADER_SIZE + 4]
+            .copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes()[..3]);
+        let data = Ipv6WryData::from_bytes(buf).unwrap();
+        assert!(data.query("::1".parse().unwrap()).is_err());
+        for i in 0..sample.len() {
+            for x in [0x00, 0x01, 0x02, 0xFF] {
+                let mut buf = sample.clone();
+                buf[i] = x;
+                if let Ok(data) = Ipv6WryData::from_bytes(buf) {
+                    for ip in ["::1", "240e::1", "ffff::1"] {
+                        data.query(ip.parse().unwrap()).ok();
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,44 +1,69 @@
+mod csv;
+pub mod db;
+pub mod error;
+pub mod ip;
 mod ip138;
 pub mod ip2region;
+mod ipv6wry;
+pub mod output;
+pub mod overlay;
+pub mod provider;
 pub mod qqwry;
 mod qqwry_lib;
+mod qqwry_writer;
+mod storage;
+#[cfg(test)]
+mod test_support;
 pub mod util;
 mod uutool;
 mod xdb;
 mod zxipv6;
 
+use std::{io, net::IpAddr, sync::Arc};
+
 use anyhow::anyhow;
 use colored::Colorize;
-use ip138::query_ip138;
-use ip2region::query_ip2region;
-use qqwry::query_qqwry;
-use uutool::query_uutool;
+use error::LookupError;
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use ip::IpRange;
+use output::{OutputFormat, Record, Serializer, Template};
+use overlay::{Overlay, LOCAL_SOURCE};
+use serde::Serialize;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
 
-// 查询服务提供方
-#[derive(Debug, Clone)]
-pub enum SearchProviderEnum<'a> {
-    IP138,
-    UUTool,
-    IP2Region(Option<&'a str>),
-    QQWry(Option<&'a str>),
-    ALL,
-}
+pub use ip138::IP138Provider;
+pub use ip2region::IP2RegionProvider;
+pub use provider::{Capabilities, LookupFuture, Provider, ProviderRegistry};
+pub use qqwry::QQWryProvider;
+pub use uutool::UUToolProvider;
+pub use zxipv6::ZXIPv6Provider;
 
-impl<'a> SearchProviderEnum<'a> {
-    pub fn get_source(&self) -> String {
-        match self {
-            Self::IP138 => "IP138.COM".to_string(),
-            Self::UUTool => "UUTOOL.CN".to_string(),
-            Self::IP2Region(_) => "IP2REGION".to_string(),
-            Self::QQWry(_) => "QQWRY".to_string(),
-            Self::ALL => "ALL".to_string(),
-        }
-    }
+// 批量查询默认的并发数
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// 批量查询的结果, 成功时包含查询来源
+pub type SearchResult = Result<(String, IPRegion), LookupError>;
+
+/// 批量查询结果的顺序
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResultOrder {
+    /// 与输入顺序一致
+    #[default]
+    Input,
+    /// 按查询完成的先后
+    Completion,
 }
 
 #[derive(Debug, Clone)]
-pub struct Searcher<'a> {
-    pub search_provider: SearchProviderEnum<'a>,
+pub struct Searcher {
+    provider: Arc<dyn Provider>,
+    overlay: Option<Arc<Overlay>>,
+    registry: ProviderRegistry,
+    serializer: Serializer,
+    concurrency: usize,
 }
 
 pub trait Search {
@@ -47,74 +72,324 @@ pub trait Search {
         ip: &str,
         echo_ip: bool,
         query_all: bool,
-    ) -> impl std::future::Future<Output = Result<(), anyhow::Error>> + Send;
+    ) -> impl std::future::Future<Output = Result<(), LookupError>> + Send;
 
     fn search(
         &mut self,
-        ip: &str,
-    ) -> impl std::future::Future<Output = Result<IPRegion, anyhow::Error>> + Send;
+        ip: IpAddr,
+    ) -> impl std::future::Future<Output = Result<IPRegion, LookupError>> + Send;
 }
 
-impl<'a> Searcher<'a> {
-    pub fn new(search_provider: SearchProviderEnum<'a>) -> Self {
-        Self { search_provider }
+impl Searcher {
+    /// 使用内置渠道
+    pub fn new<P: Provider + 'static>(provider: P) -> Self {
+        Self::with_registry(ProviderRegistry::builtin(), Arc::new(provider))
+    }
+
+    /// `registry`用于查询所有渠道及切换渠道
+    pub fn with_registry(registry: ProviderRegistry, provider: Arc<dyn Provider>) -> Self {
+        Self {
+            provider,
+            overlay: None,
+            registry,
+            serializer: Serializer::default(),
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+
+    // 设置批量查询的并发数, 最小为1
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    // 设置输出格式
+    pub fn with_format(mut self, format: OutputFormat) -> Self {
+        self.serializer = Serializer::new(format);
+        self
+    }
+
+    // 设置输出模板, 优先于输出格式
+    pub fn with_template(mut self, template: Option<Template>) -> Self {
+        self.serializer = self.serializer.with_template(template);
+        self
+    }
+
+    /// 设置本地数据, 命中时不再查询任何渠道, 来源为`LOCAL`
+    pub fn with_overlay(mut self, overlay: Overlay) -> Self {
+        self.overlay = Some(Arc::new(overlay));
+        self
+    }
+
+    pub fn overlay(&self) -> Option<&Arc<Overlay>> {
+        self.overlay.as_ref()
+    }
+
+    /// 查询结果的来源, 命中本地数据时为`LOCAL`, 否则为当前渠道
+    pub fn source(&self, ip: IpAddr) -> &str {
+        match &self.overlay {
+            Some(overlay) if overlay.lookup(ip).is_some() => LOCAL_SOURCE,
+            _ => self.provider.name(),
+        }
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.serializer.format
+    }
+
+    pub fn provider(&self) -> &Arc<dyn Provider> {
+        &self.provider
+    }
+
+    pub fn registry(&self) -> &ProviderRegistry {
+        &self.registry
+    }
+
+    /// 按名称切换到已注册的渠道
+    pub fn select(&mut self, name: &str) -> Result<&Arc<dyn Provider>, anyhow::Error> {
+        match self.registry.get(name) {
+            Some(provider) => {
+                self.provider = provider;
+                Ok(&self.provider)
+            }
+            None => Err(anyhow!(
+                "渠道参数错误: {}, 支持{}",
+                name,
+                self.registry.names().join(", ").to_lowercase()
+            )),
+        }
+    }
+
+    /// 重新加载本地数据、当前渠道及已注册渠道的离线数据库
+    pub async fn reload(&self) -> Result<(), LookupError> {
+        if let Some(overlay) = &self.overlay {
+            overlay.reload()?;
+        }
+        self.provider.reload().await?;
+        for provider in self.registry.providers() {
+            if !Arc::ptr_eq(provider, &self.provider) {
+                provider.reload().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 按当前的输出格式打印一条来源为`source`的查询结果
+    pub fn print(&mut self, source: &str, region: &IPRegion, echo_ip: bool) {
+        if self.serializer.is_text() {
+            region.display(echo_ip);
+            return;
+        }
+        let record = Record::new(source, region);
+        print!("{}", self.serializer.serialize(&[record], false));
+    }
+
+    /// 按当前的输出格式打印一条查询失败的记录, 彩色文本不输出
+    pub fn print_error(&mut self, source: &str, input: &str, error: &LookupError) {
+        if self.serializer.is_text() {
+            return;
+        }
+        let region = IPRegion::new(input.to_string(), String::new(), None);
+        let record = Record::new(source, &region).with_error(error.to_string());
+        print!("{}", self.serializer.serialize(&[record], false));
+    }
+
+    /// 逐行查询`input`中的IP, 查询结果立即输出, 忽略空行及`#`开头的行,
+    /// `query_all`为true时查询所有渠道, 返回第一个失败的错误
+    pub async fn search_lines<R: AsyncBufRead + Unpin>(
+        &mut self,
+        input: R,
+        query_all: bool,
+    ) -> Result<Option<LookupError>, io::Error> {
+        let mut lines = input.lines();
+        let mut first_err = None;
+        while let Some(line) = lines.next_line().await? {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(e) = self.search_print(line, true, query_all).await {
+                first_err.get_or_insert(e);
+            }
+        }
+        Ok(first_err)
+    }
+
+    /// 批量查询, 每个IP返回各自的结果及其来源
+    ///
+    /// 优先查询本地数据, 未命中时使用当前渠道查询, 同时进行的查询不超过
+    /// `with_concurrency`设置的并发数, 离线渠道的数据库只加载一次
+    pub fn search_many<I>(
+        &self,
+        ips: I,
+        order: ResultOrder,
+    ) -> BoxStream<'static, (IpAddr, SearchResult)>
+    where
+        I: IntoIterator<Item = IpAddr>,
+        I::IntoIter: Send + 'static,
+    {
+        self.search_stream(stream::iter(ips).map(|ip| (ip, Ok(ip))), order)
+    }
+
+    /// 批量查询流中的输入, 输入在查询的同时读取, 与`search_many`一致
+    ///
+    /// 每个输入附带的`key`随结果原样返回, IP解析失败的输入直接返回该错误
+    pub fn search_stream<S, K>(
+        &self,
+        inputs: S,
+        order: ResultOrder,
+    ) -> BoxStream<'static, (K, SearchResult)>
+    where
+        S: Stream<Item = (K, Result<IpAddr, LookupError>)> + Send + 'static,
+        K: Send + 'static,
+    {
+        let provider = self.provider.clone();
+        let overlay = self.overlay.clone();
+        let lookups = inputs.map(move |(key, ip)| {
+            let provider = provider.clone();
+            let local = match &ip {
+                Ok(ip) => overlay.as_ref().and_then(|x| x.lookup(*ip)),
+                Err(_) => None,
+            };
+            async move {
+                let res = match (ip, local) {
+                    (Err(e), _) => Err(e),
+                    (Ok(_), Some(region)) => Ok((LOCAL_SOURCE.to_string(), region)),
+                    (Ok(ip), None) => lookup(provider.as_ref(), ip)
+                        .await
+                        .map(|region| (provider.name().to_string(), region)),
+                };
+                (key, res)
+            }
+        });
+        match order {
+            ResultOrder::Input => lookups.buffered(self.concurrency).boxed(),
+            ResultOrder::Completion => lookups.buffer_unordered(self.concurrency).boxed(),
+        }
     }
 }
 
-impl Search for Searcher<'_> {
+async fn lookup(provider: &dyn Provider, ip: IpAddr) -> Result<IPRegion, LookupError> {
+    if !provider.capabilities().supports(ip) {
+        let version = if ip.is_ipv6() { "IPv6" } else { "IPv4" };
+        return Err(LookupError::Unsupported(format!("暂不支持{}", version)));
+    }
+    provider.lookup(ip).await
+}
+
+impl Search for Searcher {
     async fn search_print(
         &mut self,
         ip: &str,
         echo_ip: bool,
         query_all: bool,
-    ) -> Result<(), anyhow::Error> {
-        if !query_all {
-            match self.search(ip).await {
-                Err(e) => eprintln!("[ERR] {}.", e.to_string().red()),
-                Ok(e) => e.display(echo_ip),
+    ) -> Result<(), LookupError> {
+        let text = self.serializer.is_text();
+        let ip = match ip::parse_ip(ip) {
+            Ok(ip) => ip,
+            Err(e) => {
+                eprintln!("[ERR] {}.", e.to_string().red());
+                return Err(e);
+            }
+        };
+        // 命中本地数据时不再查询渠道, 包括查询所有渠道
+        if let Some(region) = self.overlay.as_ref().and_then(|x| x.lookup(ip)) {
+            if text {
+                region.display(echo_ip);
+                if query_all {
+                    println!("{}", LOCAL_SOURCE.bright_black());
+                }
+            } else {
+                let records = [Record::new(LOCAL_SOURCE, &region)];
+                print!("{}", self.serializer.serialize(&records, query_all));
             }
             return Ok(());
         }
-        for search_provider in [
-            SearchProviderEnum::QQWry(None),
-            SearchProviderEnum::IP2Region(None),
-            SearchProviderEnum::IP138,
-            SearchProviderEnum::UUTool,
-        ] {
-            match Searcher::new(search_provider.clone()).search(ip).await {
+        // 查询所有渠道时跳过不支持该IP类型的渠道
+        let providers = if query_all {
+            self.registry
+                .providers()
+                .iter()
+                .filter(|x| x.capabilities().supports(ip))
+                .cloned()
+                .collect()
+        } else {
+            vec![self.provider.clone()]
+        };
+        let mut results = Vec::new();
+        // 所有渠道都查询失败时返回最后一个错误
+        let mut last_err = None;
+        let mut found = false;
+        for provider in providers {
+            match lookup(provider.as_ref(), ip).await {
                 Ok(e) => {
-                    e.display(echo_ip);
+                    found = true;
+                    if text {
+                        e.display(echo_ip);
+                    } else {
+                        results.push((provider.name().to_string(), e));
+                    }
                 }
                 Err(e) => {
                     eprintln!("[ERR] {}.", e.to_string().red());
+                    last_err = Some(e);
                 }
             }
-            println!("{}", search_provider.get_source().bright_black());
+            if text && query_all {
+                println!("{}", provider.name().bright_black());
+            }
+        }
+        if !text {
+            let records = results
+                .iter()
+                .map(|(source, e)| Record::new(source, e))
+                .collect::<Vec<Record>>();
+            print!("{}", self.serializer.serialize(&records, query_all));
+        }
+        match last_err {
+            Some(e) if !found => Err(e),
+            _ => Ok(()),
         }
-        Ok(())
     }
 
-    async fn search(&mut self, ip: &str) -> Result<IPRegion, anyhow::Error> {
-        match self.search_provider.clone() {
-            SearchProviderEnum::UUTool => query_uutool(ip).await,
-            SearchProviderEnum::IP138 => query_ip138(ip).await,
-            SearchProviderEnum::QQWry(data_path) => query_qqwry(ip, data_path).await,
-            SearchProviderEnum::IP2Region(xdb_path) => query_ip2region(ip, xdb_path).await,
-            SearchProviderEnum::ALL => Err(anyhow!("Enum ALL is not implemented")),
+    async fn search(&mut self, ip: IpAddr) -> Result<IPRegion, LookupError> {
+        if let Some(region) = self.overlay.as_ref().and_then(|x| x.lookup(ip)) {
+            return Ok(region);
         }
+        lookup(self.provider.as_ref(), ip).await
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct IPRegion {
     pub ip: String,
+    // 归属地描述
     pub region: String,
+    pub country: Option<String>,
+    pub province: Option<String>,
+    pub city: Option<String>,
+    pub district: Option<String>,
     pub isp: Option<String>,
+    // 自治系统号, 如`AS4134`, 暂无渠道提供
+    pub asn: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub time_zone: Option<String>,
+    pub zip_code: Option<String>,
+    // 离线数据库中命中的IP段
+    pub range: Option<IpRange>,
+    // 本地数据的备注
+    pub notes: Option<String>,
 }
 
 impl IPRegion {
     pub fn new(ip: String, region: String, isp: Option<String>) -> Self {
-        Self { ip, region, isp }
+        Self {
+            ip,
+            region,
+            isp,
+            ..Default::default()
+        }
     }
 
     pub fn display(&self, echo_ip: bool) {
@@ -123,6 +398,9 @@ impl IPRegion {
         } else {
             self._display_no_ip();
         }
+        if let Some(notes) = &self.notes {
+            println!("{}", notes.bright_black());
+        }
     }
 
     fn _display(&self) {
@@ -150,3 +428,216 @@ impl IPRegion {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    // 按IP最后一段延迟返回, 并记录最大并发数
+    #[derive(Default)]
+    struct Slow {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    impl Provider for Slow {
+        fn name(&self) -> &str {
+            "SLOW"
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                ipv4: true,
+                ipv6: false,
+                offline: false,
+            }
+        }
+
+        fn lookup(&self, ip: IpAddr) -> LookupFuture<'_> {
+            Box::pin(async move {
+                let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_running.fetch_max(running, Ordering::SeqCst);
+                let last = match ip {
+                    IpAddr::V4(ip) => ip.octets()[3],
+                    IpAddr::V6(_) => 0,
+                };
+                tokio::time::sleep(Duration::from_millis(last as u64 * 10)).await;
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                if last == 0 {
+                    return Err(LookupError::NotFound);
+                }
+                Ok(IPRegion::new(ip.to_string(), "SLOW".to_string(), None))
+            })
+        }
+    }
+
+    // 记录查询及重新加载的次数
+    struct Counter(&'static str, AtomicUsize, AtomicUsize);
+
+    impl Provider for Counter {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                ipv4: true,
+                ipv6: true,
+                offline: true,
+            }
+        }
+
+        fn lookup(&self, ip: IpAddr) -> LookupFuture<'_> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(IPRegion::new(ip.to_string(), self.0.to_string(), None)) })
+        }
+
+        fn reload(&self) -> futures::future::BoxFuture<'_, Result<(), LookupError>> {
+            self.2.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn counter(name: &'static str) -> Arc<Counter> {
+        Arc::new(Counter(name, AtomicUsize::new(0), AtomicUsize::new(0)))
+    }
+
+    fn ips() -> Vec<IpAddr> {
+        ["1.1.1.5", "1.1.1.1", "1.1.1.0", "1.1.1.3", "::1"]
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_search_many() {
+        let provider = Arc::new(Slow::default());
+        let searcher =
+            Searcher::with_registry(ProviderRegistry::new(), provider.clone()).with_concurrency(2);
+        let res = searcher
+            .search_many(ips(), ResultOrder::Input)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(res.iter().map(|x| x.0).collect::<Vec<_>>(), ips());
+        assert!(res[0].1.is_ok());
+        assert!(matches!(res[2].1, Err(LookupError::NotFound)));
+        assert!(matches!(res[4].1, Err(LookupError::Unsupported(_))));
+        assert_eq!(provider.max_running.load(Ordering::SeqCst), 2);
+
+        let searcher = searcher.with_concurrency(8);
+        let res = searcher
+            .search_many(ips(), ResultOrder::Completion)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(res.len(), 5);
+        assert_eq!(res.last().unwrap().0, ips()[0]);
+    }
+
+    #[tokio::test]
+    async fn test_search_stream() {
+        let searcher = Searcher::with_registry(ProviderRegistry::new(), counter("A"));
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let inputs = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|x| (x, rx)) });
+        let mut results = searcher.search_stream(inputs, ResultOrder::Input);
+        // 输入未结束时已返回前面的结果
+        tx.send((1, ip::parse_ip("1.1.1.1"))).await.unwrap();
+        let (key, res) = results.next().await.unwrap();
+        assert_eq!((key, res.unwrap().0.as_str()), (1, "A"));
+        tx.send((2, ip::parse_ip("x"))).await.unwrap();
+        tx.send((3, ip::parse_ip("::1"))).await.unwrap();
+        drop(tx);
+        let res = results.collect::<Vec<_>>().await;
+        assert_eq!(res.iter().map(|x| x.0).collect::<Vec<_>>(), [2, 3]);
+        assert!(matches!(res[0].1, Err(LookupError::InvalidIp(_))));
+        assert!(res[1].1.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_search_lines() {
+        let a = counter("A");
+        let b = counter("B");
+        let mut registry = ProviderRegistry::new();
+        registry.register_arc(a.clone()).register_arc(b.clone());
+        let mut searcher =
+            Searcher::with_registry(registry, a.clone()).with_format(OutputFormat::Ndjson);
+        let input = "1.1.1.1\n\n# comment\n::1\n";
+        let err = searcher
+            .search_lines(input.as_bytes(), false)
+            .await
+            .unwrap();
+        assert!(err.is_none());
+        assert_eq!(
+            (a.1.load(Ordering::SeqCst), b.1.load(Ordering::SeqCst)),
+            (2, 0)
+        );
+        // 管道查询同样支持查询所有渠道
+        searcher.search_lines(input.as_bytes(), true).await.unwrap();
+        assert_eq!(
+            (a.1.load(Ordering::SeqCst), b.1.load(Ordering::SeqCst)),
+            (4, 2)
+        );
+        let err = searcher
+            .search_lines("x\n1.1.1.1".as_bytes(), true)
+            .await
+            .unwrap();
+        assert!(matches!(err, Some(LookupError::InvalidIp(_))));
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let (a, b, c) = (counter("A"), counter("B"), counter("C"));
+        let mut registry = ProviderRegistry::new();
+        registry.register_arc(a.clone()).register_arc(b.clone());
+        // 当前渠道及已注册渠道各重新加载一次
+        let searcher = Searcher::with_registry(registry.clone(), a.clone());
+        searcher.reload().await.unwrap();
+        assert_eq!(
+            (a.2.load(Ordering::SeqCst), b.2.load(Ordering::SeqCst)),
+            (1, 1)
+        );
+        // 未注册的当前渠道同样重新加载
+        let searcher = Searcher::with_registry(registry, c.clone());
+        searcher.reload().await.unwrap();
+        assert_eq!(
+            (
+                a.2.load(Ordering::SeqCst),
+                b.2.load(Ordering::SeqCst),
+                c.2.load(Ordering::SeqCst)
+            ),
+            (2, 2, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_overlay() {
+        let provider = Arc::new(Slow::default());
+        let overlay: Overlay = "1.1.1.0/30,办公网,专线\n::/0,VPN".parse().unwrap();
+        let mut searcher = Searcher::with_registry(ProviderRegistry::new(), provider.clone())
+            .with_overlay(overlay);
+        // 命中本地数据时不查询渠道, 包括渠道不支持的IPv6
+        let res = searcher
+            .search_many(ips(), ResultOrder::Input)
+            .collect::<Vec<_>>()
+            .await;
+        let found = |i: usize| {
+            let (source, region) = res[i].1.as_ref().unwrap();
+            (source.as_str(), region.region.as_str())
+        };
+        assert_eq!(found(0), ("SLOW", "SLOW"));
+        for i in [1, 2, 3] {
+            assert_eq!(found(i), (LOCAL_SOURCE, "办公网"));
+        }
+        assert_eq!(found(4), (LOCAL_SOURCE, "VPN"));
+        assert_eq!(provider.max_running.load(Ordering::SeqCst), 1);
+        assert_eq!(searcher.source(ips()[2]), LOCAL_SOURCE);
+        assert_eq!(searcher.source(ips()[0]), "SLOW");
+        let r = searcher.search(ips()[2]).await.unwrap();
+        assert_eq!(r.isp.as_deref(), Some("专线"));
+        assert!(searcher.search_print("1.1.1.0", false, true).await.is_ok());
+    }
+}
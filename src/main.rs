@@ -1,21 +1,40 @@
 use std::{
+    collections::HashSet,
     fs as std_fs,
     io::{self, Write},
+    net::IpAddr,
     path::PathBuf,
     process::exit,
     sync::{Arc, Mutex},
-    time::{self, Duration},
+    time,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use colored::Colorize;
+use futures::{future::BoxFuture, stream, StreamExt};
 use iprr::{
-    ip2region::{XDB_FILEPATH, XDB_URL},
-    qqwry::{QQWRY_FILEPATH, QQWRY_URL},
-    util::{clear_current_line, clear_prev_line, download_file, replace_home, wait_blink},
-    Search, Searcher,
+    db::{
+        build_xdb, convert, export, metadata, ConvertFormat, DatabaseKind, ExportFormat,
+        FieldMapping,
+    },
+    error::LookupError,
+    ip::parse_ip,
+    ip2region::{CachePolicy, XDB_FILEPATH, XDB_URL, XDB_V6_FILEPATH, XDB_V6_URL},
+    output::{OutputFormat, Template},
+    overlay::{Overlay, OVERLAY_FILEPATH},
+    qqwry::{IPV6WRY_FILEPATH, IPV6WRY_URL, QQWRY_FILEPATH, QQWRY_URL},
+    util::{download_file, replace_home, wait_blink},
+    Capabilities, IP2RegionProvider, LookupFuture, Provider, ProviderRegistry, QQWryProvider,
+    ResultOrder, Search, Searcher, DEFAULT_CONCURRENCY,
 };
-use tokio::{fs, io::AsyncWriteExt, sync::mpsc, time::sleep};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+    sync::mpsc,
+};
+
+// 批量查询时读取后等待查询的输入数
+const FILE_BUFFER: usize = 1024;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -37,6 +56,10 @@ struct Cli {
     #[arg(long)]
     ip2region_db_path: Option<String>,
 
+    /// ip2region离线数据库的缓存策略, 内存较小时可只缓存向量索引或不缓存
+    #[arg(long, value_enum, default_value_t = CachePolicy::Content)]
+    ip2region_cache: CachePolicy,
+
     /// 更新ip2region离线数据库
     #[clap(long, conflicts_with_all = vec!["uutool", "ip138", "all"])]
     ip2region_update: bool,
@@ -45,6 +68,18 @@ struct Cli {
     #[arg(long)]
     ip2region_update_url: Option<String>,
 
+    /// ip2region IPv6离线数据库文件所在地址, 默认 ~/.cache/ipr/ip2region_v6.xdb
+    #[arg(long)]
+    ip2region_v6_db_path: Option<String>,
+
+    /// 更新ip2region IPv6离线数据库
+    #[clap(long, conflicts_with_all = vec!["uutool", "ip138", "all"])]
+    ip2region_v6_update: bool,
+
+    /// ip2region IPv6离线数据库更新链接, 默认 https://cdn.jsdelivr.net/gh/lionsoul2014/ip2region/data/ip2region_v6.xdb
+    #[arg(long)]
+    ip2region_v6_update_url: Option<String>,
+
     /// 纯真离线数据库文件所在地址, 默认 ~/.cache/ipr/qqwry.dat
     #[arg(long)]
     qqwry_db_path: Option<String>,
@@ -57,25 +92,480 @@ struct Cli {
     #[arg(long)]
     qqwry_update_url: Option<String>,
 
+    /// 纯真渠道查询IPv6使用的ZX离线数据库文件所在地址, 默认 ~/.cache/ipr/ipv6wry.db
+    #[arg(long)]
+    ipv6wry_db_path: Option<String>,
+
+    /// 更新ZX IPv6离线数据库
+    #[clap(long, conflicts_with_all = vec!["uutool", "ip138", "ip2region", "all"])]
+    ipv6wry_update: bool,
+
+    /// ZX IPv6离线数据库更新链接, 默认 https://raw.githubusercontent.com/ZX-Inc/zxipdb-python/main/data/ipv6wry.db
+    #[arg(long)]
+    ipv6wry_update_url: Option<String>,
+
+    /// 本地数据文件, 每行为`cidr,label,isp,notes`, 优先于所有渠道查询, 默认 ~/.config/ipr/local.csv (存在时加载)
+    #[arg(long)]
+    local_db_path: Option<String>,
+
+    /// 查询渠道, 支持qqwry, ip2region, ip138, uutool及zxinc, 默认qqwry
+    #[clap(short, long, conflicts_with_all = vec!["uutool", "ip2region", "ip138", "all"])]
+    provider: Option<String>,
+
     /// 查询所有渠道
     #[clap(short, long, conflicts_with_all = vec!["uutool", "ip2region", "ip138"])]
     all: bool,
 
-    /// IP地址, 支持IPv4和IPv6(离线模式不支持)
+    /// 输出格式
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// 输出模板, 如 '{ip}\t{country}/{province} {isp:-unknown} ({source})'
+    #[arg(long, conflicts_with = "format")]
+    template: Option<Template>,
+
+    /// 批量查询文件中的IP, 每行一个, 忽略空行及`#`开头的行, `-`表示从stdin读取
+    #[arg(short, long, conflicts_with_all = vec!["ip", "all"])]
+    file: Option<String>,
+
+    /// 批量查询时跳过重复的IP
+    #[arg(long, requires = "file")]
+    dedupe: bool,
+
+    /// 批量查询的并发数
+    #[arg(long, default_value_t = DEFAULT_CONCURRENCY)]
+    concurrency: usize,
+
+    /// IP地址, 支持IPv4和IPv6, 也可以是整数形式、ip:port、[v6]:port或URL
     ip: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 离线数据库管理
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// 查看离线数据库的路径、大小、记录数、版本、文件头及SHA-256
+    Info {
+        /// 数据库类型, 默认查看全部
+        #[arg(value_enum)]
+        kind: Option<DatabaseKind>,
+
+        /// 以JSON格式输出
+        #[arg(long)]
+        json: bool,
+    },
+    /// 按IP顺序导出离线数据库中的所有IP段
+    Export {
+        /// 数据库类型
+        #[arg(value_enum)]
+        kind: DatabaseKind,
+
+        /// 导出格式
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+
+        /// 输出文件, 默认输出到stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// 从CSV生成xdb, 每行为`start_ip,end_ip,region`, IP段不能重叠
+    BuildXdb {
+        /// CSV文件, `-`表示从stdin读取
+        input: String,
+
+        /// 生成的xdb文件
+        #[arg(short, long)]
+        output: String,
+    },
+    /// 在纯真、xdb及CSV之间转换, 映射后字段相同的相邻IP段会被合并
+    Convert {
+        /// 输入格式
+        #[arg(long, value_enum)]
+        from: ConvertFormat,
+
+        /// 输出格式
+        #[arg(long, value_enum)]
+        to: ConvertFormat,
+
+        /// 输入文件
+        input: String,
+
+        /// 输出文件
+        output: String,
+
+        /// xdb及CSV的地区模板, 默认保持原样, 从纯真转换时为'{country:-0}|0|{province:-0}|{city:-0}|{isp:-0}'
+        #[arg(long)]
+        region_template: Option<Template>,
+
+        /// 纯真的国家模板, 默认为'国家–省份–城市'
+        #[arg(long)]
+        country_template: Option<Template>,
+
+        /// 纯真的地区模板, 默认为'{isp}'
+        #[arg(long)]
+        area_template: Option<Template>,
+
+        /// 输入CSV中地区的xdb格式版本, 2为'国家|区域|省份|城市|ISP', 3为'国家|省份|城市|ISP',
+        /// 默认IPv4为2, IPv6为3
+        #[arg(long)]
+        csv_version: Option<u16>,
+    },
+}
+
+impl Cli {
+    // 数据库路径, 未指定时使用默认路径
+    fn db_path(&self, kind: DatabaseKind) -> PathBuf {
+        let path = match kind {
+            DatabaseKind::Qqwry => &self.qqwry_db_path,
+            DatabaseKind::Ipv6wry => &self.ipv6wry_db_path,
+            DatabaseKind::Ip2region => &self.ip2region_db_path,
+            DatabaseKind::Ip2regionV6 => &self.ip2region_v6_db_path,
+        };
+        match path {
+            Some(p) => PathBuf::from(replace_home(p)),
+            None => kind.default_path(),
+        }
+    }
+}
+
+// 查询在线渠道时显示闪烁提示, 离线渠道查询很快, 不显示
+struct ProgressProvider(Arc<dyn Provider>);
+
+impl Provider for ProgressProvider {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.0.capabilities()
+    }
+
+    fn lookup(&self, ip: IpAddr) -> LookupFuture<'_> {
+        if self.0.capabilities().offline {
+            return self.0.lookup(ip);
+        }
+        Box::pin(async move {
+            let wait = wait_blink("查询中，请稍候🔎...", 3);
+            let res = self.0.lookup(ip).await;
+            wait.stop().await;
+            res
+        })
+    }
+
+    fn reload(&self) -> BoxFuture<'_, Result<(), LookupError>> {
+        self.0.reload()
+    }
+}
+
+fn kind_name(kind: DatabaseKind) -> String {
+    kind.to_possible_value()
+        .map(|x| x.get_name().to_string())
+        .unwrap_or_default()
+}
+
+// 查看离线数据库信息, 未指定类型时跳过未安装的数据库
+fn db_info(cli: &Cli, kind: Option<DatabaseKind>, json: bool) -> Result<i32, anyhow::Error> {
+    let all = kind.is_none();
+    let kinds = match kind {
+        Some(kind) => vec![kind],
+        None => DatabaseKind::ALL.to_vec(),
+    };
+    let mut code = 0;
+    let mut items = Vec::new();
+    for kind in kinds {
+        let path = cli.db_path(kind);
+        let meta = match metadata(kind, &path) {
+            Ok(meta) => meta,
+            Err(LookupError::DatabaseMissing(_)) if all => {
+                if !json {
+                    println!(
+                        "{} {}",
+                        kind_name(kind).magenta().bold(),
+                        format!("未安装 ({})", path.display()).bright_black()
+                    );
+                }
+                continue;
+            }
+            Err(e) => {
+                eprintln!("[ERR] {}: {}.", kind_name(kind), e.to_string().red());
+                if code == 0 {
+                    code = e.exit_code();
+                }
+                continue;
+            }
+        };
+        if json {
+            items.push(meta);
+            continue;
+        }
+        println!(
+            "{} {}",
+            kind_name(kind).magenta().bold(),
+            meta.path.display()
+        );
+        println!(
+            "  大小: {}字节 ({:.1}MiB)",
+            meta.size,
+            meta.size as f64 / 1048576.0
+        );
+        println!("  记录数: {}", meta.records);
+        println!("  版本: {}", meta.version.as_deref().unwrap_or("-"));
+        println!(
+            "  格式版本: {}",
+            meta.format_version
+                .map_or("-".to_string(), |x| x.to_string())
+        );
+        println!("  日期: {}", meta.date.as_deref().unwrap_or("-"));
+        println!(
+            "  文件头: {}",
+            meta.header
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        println!("  SHA-256: {}", meta.sha256);
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&items)?);
+    }
+    Ok(code)
+}
+
+// 导出离线数据库, 输出到stdout时忽略管道关闭
+fn db_export(
+    cli: &Cli,
+    kind: DatabaseKind,
+    format: ExportFormat,
+    output: Option<&str>,
+) -> Result<i32, anyhow::Error> {
+    let path = cli.db_path(kind);
+    let res = match output {
+        Some(output) => {
+            let file = std_fs::File::create(replace_home(output))?;
+            export(kind, &path, format, io::BufWriter::new(file))
+        }
+        None => export(kind, &path, format, io::BufWriter::new(io::stdout().lock())),
+    };
+    match res {
+        Ok(count) => {
+            eprintln!("{}", format!("共导出{}条记录", count).bright_black());
+            Ok(0)
+        }
+        Err(e) => {
+            if let Some(e) = e.downcast_ref::<io::Error>() {
+                if e.kind() == io::ErrorKind::BrokenPipe {
+                    return Ok(0);
+                }
+            }
+            eprintln!("[ERR] {}: {}.", kind_name(kind), e.to_string().red());
+            Ok(e.downcast_ref::<LookupError>().map_or(1, |e| e.exit_code()))
+        }
+    }
+}
+
+// 批量查询文件中的IP, 返回退出码
+//
+// 逐行读取, 读取的同时并发查询, 不需要将整个文件读入内存
+async fn search_file(
+    searcher: &mut Searcher,
+    path: &str,
+    dedupe: bool,
+) -> Result<i32, anyhow::Error> {
+    let input: Box<dyn AsyncRead + Unpin + Send> = if path == "-" {
+        Box::new(tokio::io::stdin())
+    } else {
+        Box::new(fs::File::open(replace_home(path)).await?)
+    };
+    let (tx, rx) = mpsc::channel(FILE_BUFFER);
+    let reader = tokio::spawn(async move {
+        let mut lines = BufReader::new(input).lines();
+        let mut seen = HashSet::new();
+        let mut line_no = 0;
+        while let Some(line) = lines.next_line().await? {
+            line_no += 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let ip = parse_ip(line);
+            if let Ok(ip) = ip {
+                if dedupe && !seen.insert(ip) {
+                    continue;
+                }
+            }
+            // 行号及输入随结果返回
+            if tx.send(((line_no, line.to_string()), ip)).await.is_err() {
+                break;
+            }
+        }
+        Ok::<(), io::Error>(())
+    });
+    let inputs = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|x| (x, rx)) });
+    let mut results = searcher.search_stream(inputs, ResultOrder::Input);
+    let mut total = 0;
+    // 行号, 输入, 错误
+    let mut failures = Vec::new();
+    // 每个输入输出一条记录, 失败的输入同时输出到stderr
+    while let Some(((line_no, input), res)) = results.next().await {
+        total += 1;
+        match res {
+            Ok((source, e)) => searcher.print(&source, &e, true),
+            Err(e) => {
+                eprintln!("[ERR] {}: {}.", input, e.to_string().red());
+                let source = searcher.provider().name().to_string();
+                searcher.print_error(&source, &input, &e);
+                failures.push((line_no, input, e));
+            }
+        }
+    }
+    reader.await??;
+    eprintln!(
+        "{}",
+        format!(
+            "共{}个IP, 成功{}个, 失败{}个",
+            total,
+            total - failures.len(),
+            failures.len()
+        )
+        .bright_black()
+    );
+    for (line_no, input, e) in &failures {
+        eprintln!("  第{}行 {}: {}", line_no, input, e.to_string().red());
+    }
+    Ok(failures.first().map_or(0, |x| x.2.exit_code()))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let start = time::Instant::now();
     let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Db {
+            command: DbCommand::Info { kind, json },
+        }) => exit(db_info(&cli, kind, json)?),
+        Some(Command::Db {
+            command:
+                DbCommand::Export {
+                    kind,
+                    format,
+                    ref output,
+                },
+        }) => exit(db_export(&cli, kind, format, output.as_deref())?),
+        Some(Command::Db {
+            command:
+                DbCommand::BuildXdb {
+                    ref input,
+                    ref output,
+                },
+        }) => {
+            let output = replace_home(output);
+            let res = if input == "-" {
+                build_xdb(io::stdin().lock(), &output)
+            } else {
+                std_fs::File::open(replace_home(input))
+                    .map_err(anyhow::Error::from)
+                    .and_then(|file| build_xdb(io::BufReader::new(file), &output))
+            };
+            match res {
+                Ok(count) => {
+                    println!(
+                        "{} {}",
+                        format!("已生成{}, 共{}个IP段 ✅", output, count)
+                            .green()
+                            .bold(),
+                        format!("{}ms elapsed.", start.elapsed().as_millis()).bright_black()
+                    );
+                    exit(0);
+                }
+                Err(e) => {
+                    eprintln!("[ERR] {}", e.to_string().red());
+                    exit(1);
+                }
+            }
+        }
+        Some(Command::Db {
+            command:
+                DbCommand::Convert {
+                    from,
+                    to,
+                    ref input,
+                    ref output,
+                    ref region_template,
+                    ref country_template,
+                    ref area_template,
+                    csv_version,
+                },
+        }) => {
+            let mut mapping = FieldMapping::new();
+            if let Some(t) = region_template {
+                mapping = mapping.with_region(t.clone());
+            }
+            if let Some(t) = country_template {
+                mapping = mapping.with_country(t.clone());
+            }
+            if let Some(t) = area_template {
+                mapping = mapping.with_area(t.clone());
+            }
+            if let Some(version) = csv_version {
+                mapping = mapping.with_csv_version(version);
+            }
+            let output = replace_home(output);
+            match convert(from, to, input, &output, &mapping) {
+                Ok(stats) => {
+                    println!(
+                        "{} {}",
+                        format!(
+                            "已生成{}, 读取{}个IP段(其中xdb拆分的{}个已还原), 合并{}个, 拆分{}个, 写入{}个 ✅",
+                            output,
+                            stats.read,
+                            stats.rejoined,
+                            stats.merged,
+                            stats.split,
+                            stats.written
+                        )
+                        .green()
+                        .bold(),
+                        format!("{}ms elapsed.", start.elapsed().as_millis()).bright_black()
+                    );
+                    exit(0);
+                }
+                Err(e) => {
+                    eprintln!("[ERR] {}", e.to_string().red());
+                    exit(e.downcast_ref::<LookupError>().map_or(1, |e| e.exit_code()));
+                }
+            }
+        }
+        None => {}
+    }
     // 更新离线数据库
-    if cli.ip2region_update || cli.qqwry_update {
+    if cli.ip2region_update || cli.ip2region_v6_update || cli.qqwry_update || cli.ipv6wry_update {
         let (download_url, download_dest) = if cli.ip2region_update {
             (
                 cli.ip2region_update_url.unwrap_or(XDB_URL.to_string()),
                 (*XDB_FILEPATH).clone(),
             )
+        } else if cli.ip2region_v6_update {
+            (
+                cli.ip2region_v6_update_url
+                    .unwrap_or(XDB_V6_URL.to_string()),
+                (*XDB_V6_FILEPATH).clone(),
+            )
+        } else if cli.ipv6wry_update {
+            (
+                cli.ipv6wry_update_url.unwrap_or(IPV6WRY_URL.to_string()),
+                PathBuf::from(replace_home(IPV6WRY_FILEPATH)),
+            )
         } else {
             (
                 cli.qqwry_update_url.unwrap_or(QQWRY_URL.to_string()),
@@ -83,9 +573,9 @@ async fn main() -> Result<(), anyhow::Error> {
             )
         };
         let wait = wait_blink("更新中, 请稍候🚀...", 3);
-        download_file(&download_url, &download_dest).await?;
-        wait.sender.send(true).unwrap();
-        wait.handle.await?;
+        let res = download_file(&download_url, &download_dest).await;
+        wait.stop().await;
+        res?;
         println!(
             "{} {}",
             "更新成功 ✅".green().bold(),
@@ -93,15 +583,89 @@ async fn main() -> Result<(), anyhow::Error> {
         );
         return Ok(());
     }
+    let mut registry = ProviderRegistry::builtin();
+    registry
+        .register(
+            QQWryProvider::new(cli.qqwry_db_path.clone())
+                .with_ipv6_path(cli.ipv6wry_db_path.clone()),
+        )
+        .register(
+            IP2RegionProvider::new(cli.ip2region_db_path.clone())
+                .with_ipv6_path(cli.ip2region_v6_db_path.clone())
+                .with_cache_policy(cli.ip2region_cache),
+        );
+    // 只在终端中显示查询进度, 批量查询时并发查询, 不显示每个IP的进度
+    if cli.file.is_none()
+        && (is_terminal::is_terminal(io::stdout()) || is_terminal::is_terminal(io::stderr()))
+    {
+        for provider in registry.providers().to_vec() {
+            registry.register(ProgressProvider(provider));
+        }
+    }
+    let provider_name = if cli.uutool {
+        "uutool"
+    } else if cli.ip2region {
+        "ip2region"
+    } else if cli.ip138 {
+        "ip138"
+    } else {
+        cli.provider.as_deref().unwrap_or("qqwry")
+    };
+    let provider = match registry.get(provider_name) {
+        Some(provider) => provider,
+        None => {
+            eprintln!(
+                "{}: {}, 支持{}",
+                "渠道参数错误".red(),
+                provider_name,
+                registry.names().join(", ").to_lowercase()
+            );
+            exit(1);
+        }
+    };
+    // 批量查询时JSON每个IP输出一行, 保证每个输入对应一行且可逐行解析
+    let format = match cli.format {
+        OutputFormat::Json if cli.file.is_some() => OutputFormat::Ndjson,
+        format => format,
+    };
+    let mut searcher = Searcher::with_registry(registry, provider)
+        .with_format(format)
+        .with_template(cli.template.clone())
+        .with_concurrency(cli.concurrency);
+    // 指定的本地数据文件必须存在, 默认文件不存在时跳过
+    let overlay = match &cli.local_db_path {
+        Some(path) => Some(Overlay::load(path)),
+        None if OVERLAY_FILEPATH.exists() => Some(Overlay::load(&*OVERLAY_FILEPATH)),
+        None => None,
+    };
+    match overlay {
+        Some(Ok(overlay)) => searcher = searcher.with_overlay(overlay),
+        Some(Err(e)) => {
+            eprintln!("[ERR] {}", e.to_string().red());
+            exit(e.exit_code());
+        }
+        None => {}
+    }
+    if let Some(file) = cli.file {
+        let code = search_file(&mut searcher, &file, cli.dedupe).await?;
+        exit(code);
+    }
     let ip = match cli.ip {
         Some(ip) => {
-            if !ip.contains(":") && ip.split(".").collect::<Vec<&str>>().len() != 4 {
-                eprintln!("{}: {}", "IP格式错误".red(), ip);
-                exit(1);
+            if let Err(e) = parse_ip(&ip) {
+                eprintln!("{}", e.to_string().red());
+                exit(e.exit_code());
             }
             ip
         }
         None => {
+            // stdin不是终端时逐行查询, 不输出提示符
+            if !is_terminal::is_terminal(io::stdin()) {
+                let err = searcher
+                    .search_lines(BufReader::new(tokio::io::stdin()), cli.all)
+                    .await?;
+                exit(err.map_or(0, |e| e.exit_code()));
+            }
             let (tx, mut rx) = mpsc::channel(1);
             tokio::spawn(async move {
                 loop {
@@ -112,13 +676,7 @@ async fn main() -> Result<(), anyhow::Error> {
                             if size == 0 {
                                 break;
                             }
-                            if let Err(e) = tx
-                                .send((
-                                    input.trim().to_lowercase(),
-                                    is_terminal::is_terminal(io::stdin()),
-                                ))
-                                .await
-                            {
+                            if let Err(e) = tx.send(input.trim().to_lowercase()).await {
                                 eprintln!(
                                     "{} {}",
                                     "Something went wrong 😭".red(),
@@ -138,13 +696,6 @@ async fn main() -> Result<(), anyhow::Error> {
                     }
                 }
             });
-            let mut searcher = Searcher::new(iprr::SearchProviderEnum::QQWry(Some(QQWRY_FILEPATH)));
-            // 等待20ms，从pipe读取数据完成
-            sleep(Duration::from_millis(20)).await;
-            if let Ok(input) = rx.try_recv() {
-                searcher.search_print(&input.0, true, false).await?;
-                return Ok(());
-            }
             println!(
                 "{} {}",
                 "🌍欢迎使用IP归属地查询, 请输入IP.".magenta(),
@@ -177,33 +728,16 @@ async fn main() -> Result<(), anyhow::Error> {
                 })
                 .unwrap();
             }
-            // 上次输入
-            let mut last_input = String::new();
-            // 是否来自pipe
-            let mut is_pipe = false;
             loop {
                 print!(">>> ");
                 io::stdout().flush().unwrap();
-                let input = rx.recv().await;
-                if input.is_none() {
-                    if is_pipe {
-                        clear_current_line();
-                        if !last_input.is_empty() {
-                            clear_prev_line();
-                            clear_prev_line();
-                            searcher.search_print(&last_input, true, false).await?;
-                        } else {
-                            eprintln!("[ERR] {}", "input from pipe is empty".red());
-                        }
-                        exit(0);
-                    } else {
+                let input = match rx.recv().await {
+                    Some(input) => input,
+                    None => {
                         eprintln!("[ERR] {}", "channel is close".red());
                         exit(1);
                     }
-                }
-                let input = input.unwrap();
-                is_pipe = !input.1;
-                let input = input.0;
+                };
                 if input.is_empty() {
                     continue;
                 } else if input == "q" || input == "exit" || input == "quit" {
@@ -216,44 +750,49 @@ async fn main() -> Result<(), anyhow::Error> {
                     }
                     break;
                 } else if input == "help" || input == "h" {
-                    println!("1.输入IP地址, 按回车提交查询.纯真数据库(qqwry)及uutool支持查询IPv6, 其余只支持IPv4.");
-                    println!("2.默认查询纯真数据库(qqwry), 输入`select channel`切换渠道, 变量channel支持`ip138`, `ip2region`, `uutool`及`qqwry`.");
+                    println!("1.输入IP地址, 按回车提交查询.纯真数据库(qqwry)、ip2region及uutool支持查询IPv6, zxinc只支持IPv6, 其余只支持IPv4.");
+                    println!(
+                        "2.默认查询纯真数据库(qqwry), 输入`select channel`切换渠道, 变量channel支持{}.",
+                        searcher
+                            .registry()
+                            .names()
+                            .iter()
+                            .map(|x| format!("`{}`", x.split('.').next().unwrap_or(x).to_lowercase()))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    );
                     println!("3.输入`info`或`i`查看当前查询渠道.");
                     println!("4.输入`!!`重复上一次查询.");
-                    println!("5.输入`quit`,`q`或`exit`退出查询.");
-                    println!("6.输入`help`或`h`查看帮助.");
+                    println!("5.输入`reload`重新加载离线数据库及本地数据, 如更新数据库文件后.");
+                    println!("6.输入`quit`,`q`或`exit`退出查询.");
+                    println!("7.输入`help`或`h`查看帮助.");
                     continue;
                 } else if input.starts_with("select") {
                     match input.split(" ").last() {
-                        Some(channel) => {
-                            let channel = channel.trim();
-                            if channel == "ip138" {
-                                searcher = Searcher::new(iprr::SearchProviderEnum::IP138);
-                            } else if channel == "ip2region" {
-                                searcher = Searcher::new(iprr::SearchProviderEnum::IP2Region(None));
-                            } else if channel == "uutool" {
-                                searcher = Searcher::new(iprr::SearchProviderEnum::UUTool);
-                            } else if channel == "qqwry" {
-                                searcher = Searcher::new(iprr::SearchProviderEnum::QQWry(None));
-                            } else {
-                                eprintln!("{}: {channel}", "渠道参数错误".red());
-                                continue;
+                        Some(channel) => match searcher.select(channel) {
+                            Ok(provider) => {
+                                println!("已切换到 {}", provider.name().magenta());
                             }
-                            println!(
-                                "已切换到 {}",
-                                searcher.search_provider.get_source().magenta()
-                            );
-                        }
+                            Err(e) => {
+                                eprintln!("{}", e.to_string().red());
+                            }
+                        },
                         None => {
                             eprintln!("{}", "渠道参数错误！".red());
                         }
                     }
                     continue;
                 } else if input == "info" || input == "i" {
-                    println!(
-                        "当前查询渠道 {}",
-                        searcher.search_provider.get_source().magenta(),
-                    );
+                    println!("当前查询渠道 {}", searcher.provider().name().magenta());
+                    if let Some(overlay) = searcher.overlay() {
+                        println!("本地数据 {}条", overlay.len().to_string().magenta());
+                    }
+                    continue;
+                } else if input == "reload" {
+                    match searcher.reload().await {
+                        Ok(_) => println!("已重新加载离线数据库及本地数据"),
+                        Err(e) => eprintln!("{}", e.to_string().red()),
+                    }
                     continue;
                 } else if input == "!!" {
                     if last_ip.is_empty() {
@@ -261,50 +800,79 @@ async fn main() -> Result<(), anyhow::Error> {
                         continue;
                     }
                     println!(">>> {}", last_ip.bright_black());
-                    searcher.search_print(&last_ip, false, false).await?;
+                    searcher.search_print(&last_ip, false, false).await.ok();
                     continue;
                 }
                 last_ip = input.clone();
-                last_input = last_ip.clone();
                 let last_ip_arc = Arc::clone(&last_ip_arc);
                 {
                     let mut s = last_ip_arc.lock().unwrap();
                     *s = last_ip.clone();
                 }
-                searcher.search_print(&input, false, false).await?;
+                searcher.search_print(&input, false, false).await.ok();
             }
             println!("Bye!");
             exit(0);
         }
     };
-    let mut query_all = false;
-    let ip2region_db_path = match cli.ip2region_db_path {
-        Some(e) => e,
-        None => XDB_FILEPATH.to_str().unwrap().to_string(),
-    };
-    let qqwry_db_path = match cli.qqwry_db_path {
-        Some(e) => e,
-        None => QQWRY_FILEPATH.to_string(),
-    };
-    let mut searcher = if cli.uutool {
-        Searcher::new(iprr::SearchProviderEnum::UUTool)
-    } else if cli.ip2region {
-        Searcher::new(iprr::SearchProviderEnum::IP2Region(Some(
-            &ip2region_db_path,
-        )))
-    } else if cli.ip138 {
-        Searcher::new(iprr::SearchProviderEnum::IP138)
-    } else if cli.all {
-        query_all = true;
-        Searcher::new(iprr::SearchProviderEnum::ALL)
-    } else {
-        Searcher::new(iprr::SearchProviderEnum::QQWry(Some(&qqwry_db_path)))
-    };
-    searcher.search_print(&ip, true, query_all).await?;
-    println!(
-        "{} {}",
-        searcher.search_provider.get_source().bright_black(),
-        format!("{}ms elapsed.", start.elapsed().as_millis()).bright_black(),
-    );
+    let res = searcher.search_print(&ip, true, cli.all).await;
+    if cli.format == OutputFormat::Text && cli.template.is_none() {
+        println!(
+            "{} {}",
+            if cli.all {
+                "ALL"
+            } else {
+                parse_ip(&ip).map_or(searcher.provider().name(), |ip| searcher.source(ip))
+            }
+            .bright_black(),
+            format!("{}ms elapsed.", start.elapsed().as_millis()).bright_black(),
+        );
+    }
+    if let Err(e) = res {
+        exit(e.exit_code());
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    // 记录重新加载的次数
+    #[derive(Default)]
+    struct Reloads(AtomicUsize);
+
+    impl Provider for Reloads {
+        fn name(&self) -> &str {
+            "RELOADS"
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                ipv4: true,
+                ipv6: true,
+                offline: true,
+            }
+        }
+
+        fn lookup(&self, _: IpAddr) -> LookupFuture<'_> {
+            Box::pin(async { Err(LookupError::NotFound) })
+        }
+
+        fn reload(&self) -> BoxFuture<'_, Result<(), LookupError>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    // 显示进度的渠道同样转发reload
+    #[tokio::test]
+    async fn test_progress_reload() {
+        let inner = Arc::new(Reloads::default());
+        let provider = ProgressProvider(inner.clone());
+        provider.reload().await.unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 1);
+    }
+}
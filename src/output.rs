@@ -0,0 +1,437 @@
+//! 查询结果的输出格式
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{csv, IPRegion};
+
+// 表格输出的列, 与`Record`序列化的字段保持一致
+pub const FIELDS: [&str; 18] = [
+    "ip",
+    "source",
+    "region",
+    "country",
+    "province",
+    "city",
+    "district",
+    "isp",
+    "asn",
+    "latitude",
+    "longitude",
+    "time_zone",
+    "zip_code",
+    "range_start",
+    "range_end",
+    "cidr",
+    "notes",
+    "error",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// 彩色文本
+    #[default]
+    Text,
+    /// 每次查询输出一个JSON文档, 查询所有渠道时为数组; 批量查询时与ndjson一致
+    Json,
+    /// 每条记录一行JSON
+    Ndjson,
+    Csv,
+    Tsv,
+}
+
+/// 一条查询记录, 包含查询渠道, 查询失败时包含错误信息
+#[derive(Debug, Serialize)]
+pub struct Record<'a> {
+    pub source: &'a str,
+    #[serde(flatten)]
+    pub region: &'a IPRegion,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<'a> Record<'a> {
+    pub fn new(source: &'a str, region: &'a IPRegion) -> Self {
+        Self {
+            source,
+            region,
+            error: None,
+        }
+    }
+
+    /// 查询失败的记录, `region`只需包含输入的IP
+    pub fn with_error(mut self, error: String) -> Self {
+        self.error = Some(error);
+        self
+    }
+
+    pub fn field(&self, name: &str) -> Option<String> {
+        let r = self.region;
+        match name {
+            "ip" => Some(r.ip.clone()),
+            "source" => Some(self.source.to_string()),
+            "region" => Some(r.region.clone()),
+            "country" => r.country.clone(),
+            "province" => r.province.clone(),
+            "city" => r.city.clone(),
+            "district" => r.district.clone(),
+            "isp" => r.isp.clone(),
+            "asn" => r.asn.clone(),
+            "latitude" => r.latitude.map(|x| x.to_string()),
+            "longitude" => r.longitude.map(|x| x.to_string()),
+            "time_zone" => r.time_zone.clone(),
+            "zip_code" => r.zip_code.clone(),
+            "range_start" => r.range.map(|x| x.start.to_string()),
+            "range_end" => r.range.map(|x| x.end.to_string()),
+            // 多个网段以空格分隔
+            "cidr" => r.range.map(|x| {
+                x.cidrs()
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }),
+            "notes" => r.notes.clone(),
+            "error" => self.error.clone(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field {
+        name: String,
+        default: Option<String>,
+    },
+}
+
+/// 自定义输出模板, 如`{ip}\t{country}/{province} {isp:-unknown} ({source})`
+///
+/// - `{field}`输出字段, 缺失时为空
+/// - `{field:-default}`字段缺失时输出`default`
+/// - `{{`及`}}`输出花括号, `\t`、`\n`及`\\`输出制表符、换行及反斜杠
+///
+/// 字段值中的制表符及换行会被转义为`\t`及`\n`, 反斜杠转义为`\\`, 保证每条记录只占一行且可还原
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('t') => literal.push('\t'),
+                    Some('n') => literal.push('\n'),
+                    Some('\\') => literal.push('\\'),
+                    Some(c) => {
+                        literal.push('\\');
+                        literal.push(c);
+                    }
+                    None => literal.push('\\'),
+                },
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut field = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => field.push(c),
+                            None => return Err(anyhow!("模板缺少`}}`: {}", s)),
+                        }
+                    }
+                    let (name, default) = match field.split_once(":-") {
+                        Some((name, default)) => (name.trim(), Some(default.to_string())),
+                        None => (field.trim(), None),
+                    };
+                    if !FIELDS.contains(&name) {
+                        return Err(anyhow!(
+                            "未知的模板字段`{}`, 支持: {}",
+                            name,
+                            FIELDS.join(", ")
+                        ));
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Field {
+                        name: name.to_string(),
+                        default,
+                    });
+                }
+                '}' => return Err(anyhow!("模板中存在未匹配的`}}`: {}", s)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self { segments })
+    }
+}
+
+impl Template {
+    pub fn render(&self, record: &Record) -> String {
+        self.render_with(record, escape_tsv)
+    }
+
+    /// 不转义字段值, 用于生成数据库等保留原样的场景
+    pub fn render_raw(&self, record: &Record) -> String {
+        self.render_with(record, |x| x.to_string())
+    }
+
+    fn render_with(&self, record: &Record, escape: fn(&str) -> String) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Field { name, default } => {
+                    match record.field(name).filter(|x| !x.is_empty()) {
+                        Some(v) => out.push_str(&escape(&v)),
+                        None => out.push_str(default.as_deref().unwrap_or_default()),
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// 将记录序列化为机器可读的格式, CSV/TSV的表头只输出一次
+#[derive(Debug, Clone, Default)]
+pub struct Serializer {
+    pub format: OutputFormat,
+    pub template: Option<Template>,
+    header_written: bool,
+}
+
+impl Serializer {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            template: None,
+            header_written: false,
+        }
+    }
+
+    // 设置模板后忽略输出格式
+    pub fn with_template(mut self, template: Option<Template>) -> Self {
+        self.template = template;
+        self
+    }
+
+    // 是否输出彩色文本
+    pub fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text && self.template.is_none()
+    }
+
+    /// 序列化一次查询的结果, `many`为true时JSON输出数组
+    pub fn serialize(&mut self, records: &[Record], many: bool) -> String {
+        let mut out = String::new();
+        if let Some(template) = &self.template {
+            for record in records {
+                out.push_str(&template.render(record));
+                out.push('\n');
+            }
+            return out;
+        }
+        match self.format {
+            OutputFormat::Text => {
+                for record in records {
+                    out.push_str(&record.region.region);
+                    if let Some(isp) = &record.region.isp {
+                        out.push(' ');
+                        out.push_str(isp);
+                    }
+                    out.push('\n');
+                }
+            }
+            OutputFormat::Json => {
+                let s = if many {
+                    serde_json::to_string_pretty(records)
+                } else if let Some(record) = records.first() {
+                    serde_json::to_string_pretty(record)
+                } else {
+                    return out;
+                };
+                out.push_str(&s.unwrap_or_default());
+                out.push('\n');
+            }
+            OutputFormat::Ndjson => {
+                for record in records {
+                    out.push_str(&serde_json::to_string(record).unwrap_or_default());
+                    out.push('\n');
+                }
+            }
+            OutputFormat::Csv | OutputFormat::Tsv => {
+                let (sep, escape): (&str, fn(&str) -> String) = if self.format == OutputFormat::Csv
+                {
+                    (",", csv::escape)
+                } else {
+                    ("\t", escape_tsv)
+                };
+                if !self.header_written {
+                    out.push_str(&FIELDS.join(sep));
+                    out.push('\n');
+                    self.header_written = true;
+                }
+                for record in records {
+                    let row = FIELDS
+                        .iter()
+                        .map(|f| escape(&record.field(f).unwrap_or_default()))
+                        .collect::<Vec<String>>();
+                    out.push_str(&row.join(sep));
+                    out.push('\n');
+                }
+            }
+        }
+        out
+    }
+}
+
+fn escape_tsv(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ip::IpRange;
+
+    fn region() -> IPRegion {
+        IPRegion {
+            country: Some("中国".to_string()),
+            city: Some("深圳, 南山".to_string()),
+            latitude: Some(22.5),
+            range: Some(IpRange::new(
+                "1.2.3.0".parse().unwrap(),
+                "1.2.3.9".parse().unwrap(),
+            )),
+            ..IPRegion::new(
+                "1.2.3.4".to_string(),
+                "中国深圳".to_string(),
+                Some("电信".to_string()),
+            )
+        }
+    }
+
+    #[test]
+    fn test_csv() {
+        let r = region();
+        let mut s = Serializer::new(OutputFormat::Csv);
+        let out = s.serialize(&[Record::new("QQWRY", &r)], false);
+        assert_eq!(
+            out,
+            format!(
+                "{}\n1.2.3.4,QQWRY,中国深圳,中国,,\"深圳, 南山\",,电信,,22.5,,,,1.2.3.0,1.2.3.9,1.2.3.0/29 1.2.3.8/31,,\n",
+                FIELDS.join(",")
+            )
+        );
+        // 表头只输出一次
+        let out = s.serialize(&[Record::new("QQWRY", &r)], false);
+        assert!(out.starts_with("1.2.3.4,"));
+    }
+
+    #[test]
+    fn test_tsv() {
+        let mut r = region();
+        r.region = "a\tb".to_string();
+        let out = Serializer::new(OutputFormat::Tsv).serialize(&[Record::new("QQWRY", &r)], false);
+        assert_eq!(
+            out.lines().nth(1).unwrap().split('\t').nth(2),
+            Some("a\\tb")
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let r = region();
+        let records = [Record::new("QQWRY", &r), Record::new("IP138.COM", &r)];
+        let out = Serializer::new(OutputFormat::Ndjson).serialize(&records, true);
+        assert_eq!(out.lines().count(), 2);
+        let v: serde_json::Value = serde_json::from_str(out.lines().next().unwrap()).unwrap();
+        assert_eq!(v["source"], "QQWRY");
+        assert_eq!(v["ip"], "1.2.3.4");
+        assert_eq!(v["province"], serde_json::Value::Null);
+        assert_eq!(v["asn"], serde_json::Value::Null);
+        assert_eq!(v["range"]["cidrs"][1], "1.2.3.8/31");
+        let out = Serializer::new(OutputFormat::Json).serialize(&records, true);
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(v[1]["source"], "IP138.COM");
+        let out = Serializer::new(OutputFormat::Json).serialize(&records[..1], false);
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(v["isp"], "电信");
+        assert!(v.get("error").is_none());
+    }
+
+    #[test]
+    fn test_error() {
+        let r = IPRegion::new("x".to_string(), String::new(), None);
+        let record = || Record::new("QQWRY", &r).with_error("IP格式错误".to_string());
+        let out = Serializer::new(OutputFormat::Ndjson).serialize(&[record()], false);
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(
+            (&v["ip"], &v["source"], &v["error"]),
+            (&"x".into(), &"QQWRY".into(), &"IP格式错误".into())
+        );
+        let out = Serializer::new(OutputFormat::Csv).serialize(&[record()], false);
+        assert_eq!(
+            out.lines().nth(1),
+            Some("x,QQWRY,,,,,,,,,,,,,,,,IP格式错误")
+        );
+    }
+
+    #[test]
+    fn test_template() {
+        let mut r = region();
+        r.region = "a\tb\nc".to_string();
+        let t: Template =
+            "{ip}\\t{country}/{province} {isp:-unknown} {district:-unknown} {asn:-unknown} ({source}) {region} {{x}}"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            t.render(&Record::new("QQWRY", &r)),
+            "1.2.3.4\t中国/ 电信 unknown unknown (QQWRY) a\\tb\\nc {x}"
+        );
+        let out = Serializer::new(OutputFormat::Json)
+            .with_template(Some("{ip}".parse().unwrap()))
+            .serialize(
+                &[Record::new("QQWRY", &r), Record::new("IP138.COM", &r)],
+                true,
+            );
+        assert_eq!(out, "1.2.3.4\n1.2.3.4\n");
+        // 字段中的反斜杠先转义, 与转义后的制表符区分
+        r.region = "a\\tb\tc".to_string();
+        assert_eq!(
+            "{region}"
+                .parse::<Template>()
+                .unwrap()
+                .render(&Record::new("QQWRY", &r)),
+            "a\\\\tb\\tc"
+        );
+        assert!("{foo}".parse::<Template>().is_err());
+        assert!("{ip".parse::<Template>().is_err());
+        assert!("ip}".parse::<Template>().is_err());
+    }
+}
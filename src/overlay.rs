@@ -0,0 +1,227 @@
+//! 本地覆盖数据, 如办公网、VPN及机房的IP段, 优先于所有渠道查询
+//!
+//! 文件为CSV, 每行为`cidr,label,isp,notes`, `isp`及`notes`可省略,
+//! 忽略空行、`#`开头的行及`cidr`开头的表头, 字段中包含逗号时可用双引号包裹
+
+use std::{
+    fs,
+    io::ErrorKind,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{LazyLock, RwLock},
+};
+
+use crate::{
+    csv,
+    error::LookupError,
+    ip::{Cidr, IpRange},
+    util::{non_empty, replace_home},
+    IPRegion,
+};
+
+/// 本地数据的查询来源
+pub const LOCAL_SOURCE: &str = "LOCAL";
+
+/// 默认的本地数据文件, 存在时自动加载
+pub static OVERLAY_FILEPATH: LazyLock<PathBuf> = LazyLock::new(|| {
+    let p = replace_home("~/.config/ipr/local.csv");
+    PathBuf::from(p)
+});
+
+/// 本地数据中的一条记录
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlayEntry {
+    pub cidr: Cidr,
+    pub label: String,
+    pub isp: Option<String>,
+    pub notes: Option<String>,
+}
+
+/// 本地数据, 多个网段包含同一IP时使用前缀最长的网段
+#[derive(Debug, Default)]
+pub struct Overlay {
+    path: Option<PathBuf>,
+    // 记录及其网段的IP范围, 范围在加载时计算
+    entries: RwLock<Vec<(OverlayEntry, IpRange)>>,
+}
+
+impl FromStr for Overlay {
+    type Err = LookupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            path: None,
+            entries: RwLock::new(parse(s)?),
+        })
+    }
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 从文件加载, `reload`时重新读取该文件
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LookupError> {
+        let path = PathBuf::from(replace_home(&path.as_ref().to_string_lossy()));
+        let entries = read(&path)?;
+        Ok(Self {
+            path: Some(path),
+            entries: RwLock::new(entries),
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 重新读取文件, 读取失败时保留原有数据
+    pub fn reload(&self) -> Result<(), LookupError> {
+        if let Some(path) = &self.path {
+            let entries = read(path)?;
+            *self.entries.write().unwrap() = entries;
+        }
+        Ok(())
+    }
+
+    /// 查询命中的记录, 结果的`range`为命中的网段
+    pub fn lookup(&self, ip: IpAddr) -> Option<IPRegion> {
+        let entries = self.entries.read().unwrap();
+        let (entry, range) = entries.iter().find(|x| x.1.contains(ip))?;
+        Some(IPRegion {
+            notes: entry.notes.clone(),
+            range: Some(*range),
+            ..IPRegion::new(ip.to_string(), entry.label.clone(), entry.isp.clone())
+        })
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<(OverlayEntry, IpRange)>, LookupError> {
+    let content = fs::read_to_string(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => LookupError::DatabaseMissing(path.to_path_buf()),
+        _ => LookupError::DatabaseCorrupt(format!("{}: {}", path.display(), e)),
+    })?;
+    parse(&content).map_err(|e| match e {
+        LookupError::DatabaseCorrupt(msg) => {
+            LookupError::DatabaseCorrupt(format!("{}: {}", path.display(), msg))
+        }
+        e => e,
+    })
+}
+
+// 解析后按前缀长度降序排列, 查询时第一个命中的即为最精确的网段
+fn parse(content: &str) -> Result<Vec<(OverlayEntry, IpRange)>, LookupError> {
+    let mut entries = Vec::new();
+    for record in csv::records(content.as_bytes()) {
+        let (i, record) = record.map_err(|e| LookupError::DatabaseCorrupt(e.to_string()))?;
+        let line = record.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("cidr,") {
+            continue;
+        }
+        let err = |msg: &str| LookupError::DatabaseCorrupt(format!("第{}行{}: {}", i, msg, line));
+        let fields = csv::split(line).ok_or_else(|| err("引号不匹配"))?;
+        if fields.len() < 2 || fields.len() > 4 {
+            return Err(err("格式错误, 应为`cidr,label,isp,notes`"));
+        }
+        let cidr = fields[0].parse::<Cidr>().map_err(|_| err("网段格式错误"))?;
+        let label = fields[1].trim();
+        if label.is_empty() {
+            return Err(err("名称不能为空"));
+        }
+        let field = |i: usize| fields.get(i).and_then(|x| non_empty(x));
+        let range = cidr.range();
+        let entry = OverlayEntry {
+            cidr,
+            label: label.to_string(),
+            isp: field(2),
+            notes: field(3),
+        };
+        entries.push((entry, range));
+    }
+    entries.sort_by_key(|x| std::cmp::Reverse(x.0.cidr.prefix_len));
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let overlay: Overlay = "cidr,label,isp,notes
+# 办公网
+10.0.0.0/8,内网
+10.1.0.0/16,北京办公室,联通专线,\"3F, 机房\"
+
+240e:1::/32,VPN,,on-call: #ops
+192.168.0.0/16,家庭网络,,\"路由器
+# 不是注释\"
+"
+        .parse()
+        .unwrap();
+        assert_eq!(overlay.len(), 4);
+        let r = overlay.lookup(ip("192.168.1.1")).unwrap();
+        assert_eq!(r.notes.as_deref(), Some("路由器\n# 不是注释"));
+        let r = overlay.lookup(ip("10.1.2.3")).unwrap();
+        assert_eq!(r.region, "北京办公室");
+        assert_eq!(r.isp.as_deref(), Some("联通专线"));
+        assert_eq!(r.notes.as_deref(), Some("3F, 机房"));
+        assert_eq!(r.range.unwrap().to_string(), "10.1.0.0-10.1.255.255");
+        let r = overlay.lookup(ip("10.2.0.1")).unwrap();
+        assert_eq!((r.region.as_str(), r.isp), ("内网", None));
+        let r = overlay.lookup(ip("240e:1::8")).unwrap();
+        assert_eq!(r.notes.as_deref(), Some("on-call: #ops"));
+        assert!(overlay.lookup(ip("8.8.8.8")).is_none());
+        assert!(overlay.lookup(ip("::1")).is_none());
+    }
+
+    #[test]
+    fn test_invalid() {
+        for s in [
+            "10.0.0.0/8",
+            "10.0.0.0/33,A",
+            "10.0.0.0/8,",
+            "10.0.0.0/8,\"A",
+            "10.0.0.0/8,A,B,C,D",
+        ] {
+            assert!(matches!(
+                s.parse::<Overlay>(),
+                Err(LookupError::DatabaseCorrupt(_))
+            ));
+        }
+        assert!(matches!(
+            Overlay::load("/nonexistent/local.csv"),
+            Err(LookupError::DatabaseMissing(_))
+        ));
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = TempDir::new("overlay");
+        let path = dir.join("local.csv");
+        fs::write(&path, "10.0.0.0/8,A\n").unwrap();
+        let overlay = Overlay::load(&path).unwrap();
+        assert_eq!(overlay.lookup(ip("10.0.0.1")).unwrap().region, "A");
+        fs::write(&path, "10.0.0.0/8,B\n").unwrap();
+        overlay.reload().unwrap();
+        assert_eq!(overlay.lookup(ip("10.0.0.1")).unwrap().region, "B");
+        // 读取失败时保留原有数据
+        fs::write(&path, "10.0.0.0/8\n").unwrap();
+        assert!(overlay.reload().is_err());
+        assert_eq!(overlay.lookup(ip("10.0.0.1")).unwrap().region, "B");
+    }
+}
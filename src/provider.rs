@@ -0,0 +1,176 @@
+//! 查询渠道
+//!
+//! 实现`Provider`并注册到`ProviderRegistry`即可接入新的查询渠道, 无需修改本crate
+
+use std::{fmt, net::IpAddr, sync::Arc};
+
+use futures::future::BoxFuture;
+
+use crate::{
+    error::LookupError, ip138::IP138Provider, ip2region::IP2RegionProvider, qqwry::QQWryProvider,
+    uutool::UUToolProvider, zxipv6::ZXIPv6Provider, IPRegion,
+};
+
+pub type LookupFuture<'a> = BoxFuture<'a, Result<IPRegion, LookupError>>;
+
+/// 查询渠道支持的能力
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub ipv4: bool,
+    pub ipv6: bool,
+    // 是否离线查询
+    pub offline: bool,
+}
+
+impl Capabilities {
+    pub fn supports(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(_) => self.ipv4,
+            IpAddr::V6(_) => self.ipv6,
+        }
+    }
+}
+
+pub trait Provider: Send + Sync {
+    /// 渠道名称, 如`QQWRY`, 同时作为查询结果的来源
+    fn name(&self) -> &str;
+
+    fn capabilities(&self) -> Capabilities;
+
+    fn lookup(&self, ip: IpAddr) -> LookupFuture<'_>;
+
+    /// 重新加载离线数据库, 如更新数据库文件后, 在线渠道无需实现
+    fn reload(&self) -> BoxFuture<'_, Result<(), LookupError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl fmt::Debug for dyn Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Provider")
+            .field("name", &self.name())
+            .field("capabilities", &self.capabilities())
+            .finish()
+    }
+}
+
+/// 已注册的查询渠道, 按注册顺序查询所有渠道
+#[derive(Debug, Clone, Default)]
+pub struct ProviderRegistry {
+    providers: Vec<Arc<dyn Provider>>,
+}
+
+impl ProviderRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 内置渠道: 纯真、ip2region、ip138、uutool及zxinc
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry
+            .register(QQWryProvider::default())
+            .register(IP2RegionProvider::default())
+            .register(IP138Provider)
+            .register(UUToolProvider)
+            .register(ZXIPv6Provider);
+        registry
+    }
+
+    /// 注册渠道, 同名渠道会被替换并保持原有顺序
+    pub fn register<P: Provider + 'static>(&mut self, provider: P) -> &mut Self {
+        self.register_arc(Arc::new(provider))
+    }
+
+    pub fn register_arc(&mut self, provider: Arc<dyn Provider>) -> &mut Self {
+        match self
+            .providers
+            .iter()
+            .position(|x| x.name() == provider.name())
+        {
+            Some(i) => self.providers[i] = provider,
+            None => self.providers.push(provider),
+        }
+        self
+    }
+
+    /// 按名称查找渠道, 忽略大小写, `ip138`可匹配`IP138.COM`
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Provider>> {
+        let name = name.trim();
+        self.providers
+            .iter()
+            .find(|x| {
+                x.name().eq_ignore_ascii_case(name)
+                    || x.name()
+                        .split('.')
+                        .next()
+                        .is_some_and(|x| x.eq_ignore_ascii_case(name))
+            })
+            .cloned()
+    }
+
+    pub fn providers(&self) -> &[Arc<dyn Provider>] {
+        &self.providers
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.providers.iter().map(|x| x.name()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Dummy(&'static str);
+
+    impl Provider for Dummy {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                ipv4: true,
+                ipv6: false,
+                offline: true,
+            }
+        }
+
+        fn lookup(&self, ip: IpAddr) -> LookupFuture<'_> {
+            Box::pin(async move { Ok(IPRegion::new(ip.to_string(), self.0.to_string(), None)) })
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = ProviderRegistry::builtin();
+        assert_eq!(
+            registry.names(),
+            vec!["QQWRY", "IP2REGION", "IP138.COM", "UUTOOL.CN", "ZXINC"]
+        );
+        assert_eq!(registry.get("ip138").unwrap().name(), "IP138.COM");
+        assert_eq!(registry.get("qqwry").unwrap().name(), "QQWRY");
+        assert!(registry.get("foo").is_none());
+        registry.register(Dummy("CORP")).register(Dummy("QQWRY"));
+        assert_eq!(
+            registry.names(),
+            vec![
+                "QQWRY",
+                "IP2REGION",
+                "IP138.COM",
+                "UUTOOL.CN",
+                "ZXINC",
+                "CORP"
+            ]
+        );
+        let r = futures::executor::block_on(
+            registry
+                .get("qqwry")
+                .unwrap()
+                .lookup("1.2.3.4".parse().unwrap()),
+        )
+        .unwrap();
+        assert_eq!(r.region, "QQWRY");
+    }
+}
@@ -1,12 +1,17 @@
 //! 纯真数据库https://github.com/FW27623/qqwry
-use std::path::PathBuf;
+use std::{io, net::IpAddr, path::PathBuf, sync::Arc};
 
-use anyhow::anyhow;
+use futures::future::BoxFuture;
+use tokio::sync::RwLock;
 
+pub use crate::ipv6wry::{Ipv6GeoInfo, Ipv6Records, Ipv6WryData};
+pub use crate::qqwry_lib::{CorruptDatabase, IpGeoInfo, QQWryData, Records};
+pub use crate::qqwry_writer::QQWryWriter;
 use crate::{
-    qqwry_lib::QQWryData,
-    util::{download_file, replace_home, wait_blink},
-    zxipv6::query_zxipv6,
+    error::LookupError,
+    ip::IpRange,
+    provider::{Capabilities, LookupFuture, Provider},
+    util::{download_database, non_empty, replace_home},
     IPRegion,
 };
 
@@ -14,39 +19,308 @@ pub const QQWRY_URL: &str = "https://raw.githubusercontent.com/FW27623/qqwry/mai
 
 pub const QQWRY_FILEPATH: &str = "~/.cache/ipr/qqwry.dat";
 
-pub async fn query_qqwry(ip: &str, data_path: Option<&str>) -> Result<IPRegion, anyhow::Error> {
-    if ip.contains(":") {
-        return query_zxipv6(ip).await;
-    }
-    let wait = wait_blink("查询中，请稍候🔎...", 3);
-    let data_path = replace_home(data_path.unwrap_or(QQWRY_FILEPATH));
-    if !PathBuf::from(data_path.clone()).exists() {
-        download_file(QQWRY_URL, &PathBuf::from(replace_home(QQWRY_FILEPATH))).await?;
-    }
-    wait.sender.send(true).unwrap();
-    wait.handle.await?;
-    let client = QQWryData::new(PathBuf::from(data_path))?;
-    let res = client.query(ip.parse()?);
-    match res {
-        Some(res) => Ok(IPRegion::new(
+pub const IPV6WRY_URL: &str =
+    "https://raw.githubusercontent.com/ZX-Inc/zxipdb-python/main/data/ipv6wry.db";
+
+pub const IPV6WRY_FILEPATH: &str = "~/.cache/ipr/ipv6wry.db";
+
+/// 纯真离线数据库, IPv6使用ZX的ipv6wry.db离线查询
+///
+/// 数据库在第一次查询时加载, 之后的查询(包括clone出的渠道)复用同一份数据,
+/// 更新数据库文件后调用`reload`重新加载
+#[derive(Debug, Clone, Default)]
+pub struct QQWryProvider {
+    data_path: Option<String>,
+    ipv6_path: Option<String>,
+    data: Arc<RwLock<Option<Arc<QQWryData>>>>,
+    ipv6_data: Arc<RwLock<Option<Arc<Ipv6WryData>>>>,
+}
+
+impl QQWryProvider {
+    pub fn new(data_path: Option<String>) -> Self {
+        Self {
+            data_path,
+            ..Default::default()
+        }
+    }
+
+    /// 使用已加载的数据库, `reload`时从`data_path`(默认 ~/.cache/ipr/qqwry.dat)重新加载
+    pub fn with_data(data: Arc<QQWryData>, data_path: Option<String>) -> Self {
+        Self {
+            data_path,
+            data: Arc::new(RwLock::new(Some(data))),
+            ..Default::default()
+        }
+    }
+
+    /// IPv6数据库文件所在地址, 默认 ~/.cache/ipr/ipv6wry.db
+    pub fn with_ipv6_path(mut self, ipv6_path: Option<String>) -> Self {
+        self.ipv6_path = ipv6_path;
+        self
+    }
+
+    /// 返回已加载的数据库, 未加载时先加载
+    pub async fn data(&self) -> Result<Arc<QQWryData>, LookupError> {
+        if let Some(data) = self.data.read().await.as_ref() {
+            return Ok(data.clone());
+        }
+        let mut data = self.data.write().await;
+        if let Some(data) = data.as_ref() {
+            return Ok(data.clone());
+        }
+        let loaded = Arc::new(load_qqwry(self.data_path.as_deref()).await?);
+        *data = Some(loaded.clone());
+        Ok(loaded)
+    }
+
+    /// 返回已加载的IPv6数据库, 未加载时先加载
+    pub async fn ipv6_data(&self) -> Result<Arc<Ipv6WryData>, LookupError> {
+        if let Some(data) = self.ipv6_data.read().await.as_ref() {
+            return Ok(data.clone());
+        }
+        let mut data = self.ipv6_data.write().await;
+        if let Some(data) = data.as_ref() {
+            return Ok(data.clone());
+        }
+        let loaded = Arc::new(load_ipv6wry(self.ipv6_path.as_deref()).await?);
+        *data = Some(loaded.clone());
+        Ok(loaded)
+    }
+}
+
+impl Provider for QQWryProvider {
+    fn name(&self) -> &str {
+        "QQWRY"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ipv4: true,
+            ipv6: true,
+            offline: true,
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> LookupFuture<'_> {
+        Box::pin(async move {
+            if ip.is_ipv6() {
+                let data = self.ipv6_data().await?;
+                return query_ipv6wry_data(&data, ip);
+            }
+            let data = self.data().await?;
+            query_qqwry_data(&data, ip)
+        })
+    }
+
+    fn reload(&self) -> BoxFuture<'_, Result<(), LookupError>> {
+        Box::pin(async move {
+            let mut data = self.data.write().await;
+            // 未加载过的数据库在下次查询时再加载
+            if data.is_some() {
+                *data = Some(Arc::new(load_qqwry(self.data_path.as_deref()).await?));
+            }
+            let mut ipv6_data = self.ipv6_data.write().await;
+            if ipv6_data.is_some() {
+                *ipv6_data = Some(Arc::new(load_ipv6wry(self.ipv6_path.as_deref()).await?));
+            }
+            Ok(())
+        })
+    }
+}
+
+// 加载数据库, 默认路径的数据库不存在时自动下载
+async fn load_qqwry(data_path: Option<&str>) -> Result<QQWryData, LookupError> {
+    let data_path = PathBuf::from(replace_home(data_path.unwrap_or(QQWRY_FILEPATH)));
+    if !data_path.exists() && data_path == replace_home(QQWRY_FILEPATH) {
+        download_database(QQWRY_URL, &data_path).await?;
+    }
+    QQWryData::new(&data_path).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => LookupError::DatabaseCorrupt(e.to_string()),
+        _ => LookupError::DatabaseMissing(data_path),
+    })
+}
+
+// 加载IPv6数据库, 默认路径的数据库不存在时自动下载
+async fn load_ipv6wry(ipv6_path: Option<&str>) -> Result<Ipv6WryData, LookupError> {
+    let ipv6_path = PathBuf::from(replace_home(ipv6_path.unwrap_or(IPV6WRY_FILEPATH)));
+    if !ipv6_path.exists() && ipv6_path == replace_home(IPV6WRY_FILEPATH) {
+        download_database(IPV6WRY_URL, &ipv6_path).await?;
+    }
+    Ipv6WryData::new(&ipv6_path).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => LookupError::DatabaseCorrupt(e.to_string()),
+        _ => LookupError::DatabaseMissing(ipv6_path),
+    })
+}
+
+/// IPv6使用`ipv6_path`(默认 ~/.cache/ipr/ipv6wry.db)的ipv6wry.db
+pub async fn query_qqwry(
+    ip: IpAddr,
+    data_path: Option<&str>,
+    ipv6_path: Option<&str>,
+) -> Result<IPRegion, LookupError> {
+    if ip.is_ipv6() {
+        return query_ipv6wry_data(&load_ipv6wry(ipv6_path).await?, ip);
+    }
+    query_qqwry_data(&load_qqwry(data_path).await?, ip)
+}
+
+fn query_qqwry_data(data: &QQWryData, ip: IpAddr) -> Result<IPRegion, LookupError> {
+    let ip_addr = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Err(LookupError::Unsupported("暂不支持IPv6".to_string())),
+    };
+    match data.query(ip_addr) {
+        Ok(res) => Ok(to_region(ip.to_string(), &res)),
+        Err(e) => Err(LookupError::DatabaseCorrupt(e.to_string())),
+    }
+}
+
+// 纯真记录转为查询结果, 新版数据拆分出国家、省份及城市
+fn to_region(ip: String, res: &IpGeoInfo) -> IPRegion {
+    let (country, province, city) = split_location(&res.country);
+    IPRegion {
+        country,
+        province,
+        city,
+        range: Some(IpRange::new(res.start.into(), res.end.into())),
+        ..IPRegion::new(
+            ip,
+            clean_field(&res.country),
+            non_empty(&clean_field(&res.area)),
+        )
+    }
+}
+
+fn query_ipv6wry_data(data: &Ipv6WryData, ip: IpAddr) -> Result<IPRegion, LookupError> {
+    let ip_addr = match ip {
+        IpAddr::V6(ip) => ip,
+        IpAddr::V4(_) => return Err(LookupError::Unsupported("只支持IPv6".to_string())),
+    };
+    let res = data
+        .query(ip_addr)
+        .map_err(|e| LookupError::DatabaseCorrupt(e.to_string()))?;
+    // 国家、省份及城市以制表符分隔, 如`中国\t广东省\t深圳市`
+    let mut parts = res.country.split('\t').map(non_empty);
+    Ok(IPRegion {
+        country: parts.next().flatten(),
+        province: parts.next().flatten(),
+        city: parts.next().flatten(),
+        range: Some(IpRange::new(res.start.into(), res.end.into())),
+        ..IPRegion::new(
             ip.to_string(),
-            res.country
-                .replace("-", " ")
-                .replace("–", "")
-                .replace("_", " ")
-                .replace("CZ88.NET", "")
-                .trim()
-                .to_string(),
-            Some(
-                res.area
-                    .replace("-", " ")
-                    .replace("–", "")
-                    .replace("_", " ")
-                    .replace("CZ88.NET", "")
-                    .trim()
-                    .to_string(),
-            ),
-        )),
-        None => Err(anyhow!("未查询到结果！")),
+            res.country.split_whitespace().collect::<Vec<_>>().join(" "),
+            non_empty(&res.area.replace('\t', " ")),
+        )
+    })
+}
+
+fn clean_field(s: &str) -> String {
+    s.replace("-", " ")
+        .replace("–", "")
+        .replace("_", " ")
+        .replace("CZ88.NET", "")
+        .trim()
+        .to_string()
+}
+
+// 新版纯真数据库以`–`分隔国家、省份及城市, 如`中国–广东–深圳`
+fn split_location(s: &str) -> (Option<String>, Option<String>, Option<String>) {
+    if !s.contains('–') {
+        return (None, None, None);
+    }
+    let mut parts = s.split('–').map(non_empty);
+    (
+        parts.next().flatten(),
+        parts.next().flatten(),
+        parts.next().flatten(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    // 生成只有一个地区的纯真数据库
+    fn write_qqwry(path: &std::path::Path, country: &str) {
+        let mut writer = QQWryWriter::new();
+        writer
+            .add(
+                "0.0.0.0".parse().unwrap(),
+                "255.255.255.255".parse().unwrap(),
+                country,
+                "",
+            )
+            .unwrap();
+        writer.write(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_provider_reload() {
+        let dir = TempDir::new("qqwry-reload");
+        let path = dir.join("qqwry.dat");
+        write_qqwry(&path, "A");
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let provider = QQWryProvider::new(Some(path.to_string_lossy().to_string()));
+        // 未加载时reload不加载数据库
+        provider.reload().await.unwrap();
+        assert!(provider.data.read().await.is_none());
+
+        // 查询及clone出的渠道复用同一份数据
+        assert_eq!(provider.lookup(ip).await.unwrap().region, "A");
+        let cloned = provider.clone();
+        assert!(Arc::ptr_eq(
+            &provider.data().await.unwrap(),
+            &cloned.data().await.unwrap()
+        ));
+        let before = provider.data().await.unwrap();
+
+        // 替换文件后reload重新加载, clone出的渠道同样生效
+        write_qqwry(&path, "B");
+        assert_eq!(provider.lookup(ip).await.unwrap().region, "A");
+        provider.reload().await.unwrap();
+        assert!(!Arc::ptr_eq(&before, &provider.data().await.unwrap()));
+        assert_eq!(cloned.lookup(ip).await.unwrap().region, "B");
+
+        // 文件不存在时reload失败
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            provider.reload().await,
+            Err(LookupError::DatabaseMissing(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_query_qqwry() {
+        let err = query_qqwry(
+            "::1".parse().unwrap(),
+            None,
+            Some("/nonexistent/ipv6wry.db"),
+        )
+        .await
+        .unwrap_err();
+        match err {
+            LookupError::DatabaseMissing(path) => {
+                assert_eq!(path, PathBuf::from("/nonexistent/ipv6wry.db"))
+            }
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_split_location() {
+        assert_eq!(
+            split_location("中国–广东–深圳"),
+            (
+                Some("中国".to_string()),
+                Some("广东".to_string()),
+                Some("深圳".to_string())
+            )
+        );
+        assert_eq!(
+            split_location("美国–"),
+            (Some("美国".to_string()), None, None)
+        );
+        assert_eq!(split_location("局域网"), (None, None, None));
     }
 }
@@ -1,127 +1,224 @@
 //! copy from [https://github.com/sybblow/rust-qqwry](https://github.com/sybblow/rust-qqwry)
-use std::fs::File;
-use std::io::Read;
-use std::mem;
+use std::fmt;
+use std::io;
 use std::net::Ipv4Addr;
 use std::path::Path;
 
 use encoding::all::GBK;
 use encoding::{DecoderTrap, Encoding};
 
+use crate::storage::Storage;
+
+/// 数据库损坏, `offset`为出错的位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptDatabase {
+    pub offset: usize,
+}
+
+impl fmt::Display for CorruptDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "corrupt database at offset {}", self.offset)
+    }
+}
+
+impl std::error::Error for CorruptDatabase {}
+
+impl From<CorruptDatabase> for io::Error {
+    fn from(e: CorruptDatabase) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+const INDEX_SIZE: usize = 7;
+
 #[derive(Debug)]
 pub struct IpGeoInfo {
     pub country: String,
     pub area: String,
+    // 命中记录的IP段
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
 }
 
 pub struct QQWryData {
-    cache: Vec<u8>,
+    cache: Storage,
 }
 
-macro_rules! opt_try {
-    ($e:expr) => {
-        match $e {
-            Some(e) => e,
-            None => return None,
-        }
-    };
+impl std::fmt::Debug for QQWryData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QQWryData")
+            .field("size", &self.cache.len())
+            .finish()
+    }
 }
 
 impl QQWryData {
-    pub fn new<P: AsRef<Path>>(path: P) -> std::io::Result<QQWryData> {
-        let mut cache: Vec<u8> = Vec::new();
-        File::open(path)?.read_to_end(&mut cache)?;
+    /// 开启`mmap`特性时通过mmap加载, 否则读入内存, 索引区不完整时返回`InvalidData`
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<QQWryData> {
+        Ok(QQWryData::from_storage(Storage::open(path)?)?)
+    }
+
+    /// 读入内存
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<QQWryData> {
+        Ok(QQWryData::from_storage(Storage::read(path)?)?)
+    }
+
+    /// 通过mmap加载, 多个进程共享同一份页缓存
+    #[cfg(feature = "mmap")]
+    pub fn mmap<P: AsRef<Path>>(path: P) -> io::Result<QQWryData> {
+        Ok(QQWryData::from_storage(Storage::mmap(path)?)?)
+    }
+
+    pub fn from_bytes(cache: Vec<u8>) -> Result<QQWryData, CorruptDatabase> {
+        QQWryData::from_storage(cache.into())
+    }
+
+    // 检查文件头中的索引区范围
+    fn from_storage(cache: Storage) -> Result<QQWryData, CorruptDatabase> {
+        let data = QQWryData { cache };
+        let idx_first = data.read_u32(0)? as usize;
+        let idx_last = data.read_u32(4)? as usize;
+        if idx_first > idx_last || !(idx_last - idx_first).is_multiple_of(INDEX_SIZE) {
+            return Err(CorruptDatabase { offset: 0 });
+        }
+        data.bytes(idx_last, INDEX_SIZE)?;
+        Ok(data)
+    }
+
+    pub fn query(&self, ip_addr: Ipv4Addr) -> Result<IpGeoInfo, CorruptDatabase> {
+        self.record(self.find_index(ip_addr)?)
+    }
 
-        Ok(QQWryData { cache })
+    /// 按IP顺序遍历所有记录, 遇到错误后停止
+    pub fn records(&self) -> Records<'_> {
+        let (idx_first, idx_last) = self.index_range();
+        Records {
+            data: self,
+            idx: idx_first,
+            idx_last,
+        }
     }
 
-    pub fn query(&self, ip_addr: Ipv4Addr) -> Option<IpGeoInfo> {
-        let idx_found = self.find_index(ip_addr);
-        let tmp_range = &self.cache[(idx_found + 4)..];
-        let record_offset = read_u24(tmp_range) as usize;
+    // 读取第`idx_found`字节处的索引指向的记录
+    fn record(&self, idx_found: usize) -> Result<IpGeoInfo, CorruptDatabase> {
+        let start = Ipv4Addr::from(self.read_u32(idx_found)?);
+        let record_offset = self.read_u24(idx_found + 4)?;
+        let end = Ipv4Addr::from(self.read_u32(record_offset)?);
 
         let country: String;
-        // Read country information
-        let mut search_range = &self.cache[(record_offset + 4)..];
-        match read_u8(search_range) {
+        // Read country information, skip 4 bytes end ip
+        let mut offset = record_offset + 4;
+        match self.read_u8(offset)? {
             0x01 => {
-                let country_offset = read_u24(&search_range[1..]) as usize;
-                search_range = &self.cache[country_offset..];
-                match read_u8(search_range) {
+                let country_offset = self.read_u24(offset + 1)?;
+                match self.read_u8(country_offset)? {
                     0x02 => {
-                        search_range = self.jump_by_lookaside(search_range);
-                        country = opt_try!(get_gbk_cstring(search_range));
-                        search_range = &self.cache[(country_offset + 4)..];
+                        country = self.read_cstring(self.read_u24(country_offset + 1)?)?.0;
+                        offset = country_offset + 4;
                     }
                     _ => {
-                        let cstr = opt_try!(get_cstring_bytes(search_range));
-                        let len = cstr.len() + 1;
-                        country = opt_try!(decode_gbk_bytes(cstr));
-                        search_range = &search_range[len..];
+                        let (cstr, len) = self.read_cstring(country_offset)?;
+                        country = cstr;
+                        offset = country_offset + len + 1;
                     }
                 }
             }
             0x02 => {
-                search_range = self.jump_by_lookaside(search_range);
-                country = opt_try!(get_gbk_cstring(search_range));
+                country = self.read_cstring(self.read_u24(offset + 1)?)?.0;
                 // Skip 4 bytes ip and 4 bytes country offset
-                search_range = &self.cache[(record_offset + 8)..];
+                offset = record_offset + 8;
             }
             _ => {
-                let cstr = opt_try!(get_cstring_bytes(search_range));
-                let len = cstr.len() + 1;
-                country = opt_try!(decode_gbk_bytes(cstr));
-                search_range = &search_range[len..];
+                let (cstr, len) = self.read_cstring(offset)?;
+                country = cstr;
+                offset += len + 1;
             }
         }
 
-        let area: String;
         // Read area information
-        match read_u8(search_range) {
-            0x00 => {
-                area = "".to_string();
-            }
-            0x01 | 0x02 => {
-                search_range = self.jump_by_lookaside(search_range);
-                area = opt_try!(get_gbk_cstring(search_range));
-            }
-            _ => {
-                area = opt_try!(get_gbk_cstring(search_range));
-            }
-        }
+        let area = match self.read_u8(offset)? {
+            0x00 => "".to_string(),
+            0x01 | 0x02 => self.read_cstring(self.read_u24(offset + 1)?)?.0,
+            _ => self.read_cstring(offset)?.0,
+        };
 
-        Some(IpGeoInfo { country, area })
+        Ok(IpGeoInfo {
+            country,
+            area,
+            start,
+            end,
+        })
     }
 
-    fn find_index(&self, ip_addr: Ipv4Addr) -> usize {
+    fn find_index(&self, ip_addr: Ipv4Addr) -> Result<usize, CorruptDatabase> {
         let ip_addr = u32::from(ip_addr);
 
-        let idx_first = read_u32(&self.cache[..]);
-        let idx_last = read_u32(&self.cache[4..]);
+        let idx_first = self.read_u32(0)? as usize;
+        let idx_last = self.read_u32(4)? as usize;
 
-        let mut idx_found: u32 = idx_last;
-        let mut h = (idx_last - idx_first) / 7;
+        let mut h = (idx_last - idx_first) / INDEX_SIZE;
         let mut l = 0;
 
         while l <= h {
             let m = (l + h) / 2;
-            let tmp_range = &self.cache[(idx_first + m * 7) as usize..];
-            if ip_addr < read_u32(tmp_range) {
+            let idx = idx_first + m * INDEX_SIZE;
+            if ip_addr < self.read_u32(idx)? {
+                if m == 0 {
+                    break;
+                }
                 h = m - 1;
-            } else if ip_addr > read_u32(&self.cache[read_u24(&tmp_range[4..]) as usize..]) {
+            } else if ip_addr > self.read_u32(self.read_u24(idx + 4)?)? {
                 l = m + 1;
             } else {
-                idx_found = idx_first + m * 7;
-                break;
+                return Ok(idx);
             }
         }
 
-        idx_found as usize
+        Ok(idx_last)
+    }
+
+    #[inline]
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], CorruptDatabase> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.cache.get(offset..end))
+            .ok_or(CorruptDatabase { offset })
+    }
+
+    #[inline]
+    fn read_u32(&self, offset: usize) -> Result<u32, CorruptDatabase> {
+        self.bytes(offset, 4).map(read_u32)
+    }
+
+    #[inline]
+    fn read_u24(&self, offset: usize) -> Result<usize, CorruptDatabase> {
+        self.bytes(offset, 3).map(|x| read_u24(x) as usize)
     }
 
     #[inline]
-    fn jump_by_lookaside(&self, range: &[u8]) -> &[u8] {
-        &self.cache[read_u24(&range[1..]) as usize..]
+    fn read_u8(&self, offset: usize) -> Result<u8, CorruptDatabase> {
+        self.bytes(offset, 1).map(|x| x[0])
+    }
+
+    // 返回字符串及其字节长度(不含结尾的0)
+    fn read_cstring(&self, offset: usize) -> Result<(String, usize), CorruptDatabase> {
+        let corrupt = CorruptDatabase { offset };
+        let bytes = self.cache.get(offset..).ok_or(corrupt)?;
+        let cstr = get_cstring_bytes(bytes).ok_or(corrupt)?;
+        Ok((decode_gbk_bytes(cstr).ok_or(corrupt)?, cstr.len()))
+    }
+
+    /// 索引区的开始及结束位置, 即文件头的两个u32
+    pub fn index_range(&self) -> (usize, usize) {
+        let idx_first = self.read_u32(0).unwrap_or_default() as usize;
+        let idx_last = self.read_u32(4).unwrap_or_default() as usize;
+        (idx_first, idx_last)
+    }
+
+    /// 记录数
+    pub fn count(&self) -> usize {
+        let (idx_first, idx_last) = self.index_range();
+        (idx_last - idx_first) / INDEX_SIZE + 1
     }
 
     #[allow(unused)]
@@ -131,19 +228,38 @@ impl QQWryData {
     }
 }
 
-#[inline]
-fn read_u32(buf: &[u8]) -> u32 {
-    unsafe { mem::transmute([buf[0], buf[1], buf[2], buf[3]]) }
+/// 所有记录的迭代器, 见[`QQWryData::records`]
+pub struct Records<'a> {
+    data: &'a QQWryData,
+    // 下一条索引的位置
+    idx: usize,
+    idx_last: usize,
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<IpGeoInfo, CorruptDatabase>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx > self.idx_last {
+            return None;
+        }
+        let res = self.data.record(self.idx);
+        self.idx = match res {
+            Ok(_) => self.idx + INDEX_SIZE,
+            Err(_) => usize::MAX,
+        };
+        Some(res)
+    }
 }
 
 #[inline]
-fn read_u24(buf: &[u8]) -> u32 {
-    unsafe { mem::transmute([buf[0], buf[1], buf[2], 0]) }
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
 }
 
 #[inline]
-fn read_u8(buf: &[u8]) -> u32 {
-    buf[0] as u32
+fn read_u24(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], 0])
 }
 
 #[inline]
@@ -156,6 +272,7 @@ fn decode_gbk_bytes(bytes: &[u8]) -> Option<String> {
     GBK.decode(bytes, DecoderTrap::Replace).ok()
 }
 
+#[allow(unused)]
 #[inline]
 fn get_gbk_cstring(buf: &[u8]) -> Option<String> {
     get_cstring_bytes(buf).and_then(decode_gbk_bytes)
@@ -163,7 +280,10 @@ fn get_gbk_cstring(buf: &[u8]) -> Option<String> {
 
 #[cfg(test)]
 mod test {
-    use super::{get_gbk_cstring, read_u24, read_u32};
+    use encoding::EncoderTrap;
+
+    use super::*;
+    use crate::test_support::XorShift;
 
     #[test]
     fn it_works() {
@@ -177,4 +297,148 @@ mod test {
         );
         assert_eq!(get_gbk_cstring(&[0xc4, 0xe3, 0xba, 0xc3]), None);
     }
+
+    fn gbk(s: &str) -> Vec<u8> {
+        let mut x = GBK.encode(s, EncoderTrap::Strict).unwrap();
+        x.push(0);
+        x
+    }
+
+    fn u24(x: usize) -> [u8; 3] {
+        let b = (x as u32).to_le_bytes();
+        [b[0], b[1], b[2]]
+    }
+
+    // 3条记录: 直接存储、国家重定向(0x02)及完全重定向(0x01)
+    fn sample() -> Vec<u8> {
+        let mut buf = vec![0u8; 8];
+        let r1 = buf.len();
+        buf.extend_from_slice(&0x00FF_FFFFu32.to_le_bytes());
+        buf.extend(gbk("中国–广东–深圳"));
+        buf.extend(gbk("电信"));
+        let r2 = buf.len();
+        buf.extend_from_slice(&0x7FFF_FFFFu32.to_le_bytes());
+        buf.push(0x02);
+        buf.extend(u24(r1 + 4));
+        buf.extend(gbk("联通"));
+        let r3 = buf.len();
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        buf.push(0x01);
+        buf.extend(u24(r1 + 4));
+        let idx_first = buf.len();
+        for (start, offset) in [(0u32, r1), (0x0100_0000, r2), (0x8000_0000, r3)] {
+            buf.extend_from_slice(&start.to_le_bytes());
+            buf.extend(u24(offset));
+        }
+        let idx_last = buf.len() - INDEX_SIZE;
+        buf[0..4].copy_from_slice(&(idx_first as u32).to_le_bytes());
+        buf[4..8].copy_from_slice(&(idx_last as u32).to_le_bytes());
+        buf
+    }
+
+    fn query_all(buf: Vec<u8>) -> Vec<Result<IpGeoInfo, CorruptDatabase>> {
+        let Ok(data) = QQWryData::from_bytes(buf) else {
+            return Vec::new();
+        };
+        [
+            "0.0.0.1",
+            "0.255.255.255",
+            "1.2.3.4",
+            "127.0.0.1",
+            "200.1.1.1",
+        ]
+        .iter()
+        .map(|x| data.query(x.parse().unwrap()))
+        .collect()
+    }
+
+    #[test]
+    fn test_query() {
+        let data = QQWryData::from_bytes(sample()).unwrap();
+        let r = data.query("0.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!(
+            (r.country.as_str(), r.area.as_str()),
+            ("中国–广东–深圳", "电信")
+        );
+        assert_eq!(
+            (r.start, r.end),
+            (Ipv4Addr::from(0), Ipv4Addr::from(0x00FF_FFFF))
+        );
+        let r = data.query("10.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(
+            (r.country.as_str(), r.area.as_str()),
+            ("中国–广东–深圳", "联通")
+        );
+        assert_eq!(r.end, Ipv4Addr::from(0x7FFF_FFFF));
+        let r = data.query("255.255.255.255".parse().unwrap()).unwrap();
+        assert_eq!(
+            (r.country.as_str(), r.area.as_str()),
+            ("中国–广东–深圳", "电信")
+        );
+    }
+
+    #[test]
+    fn test_records() {
+        let data = QQWryData::from_bytes(sample()).unwrap();
+        assert_eq!(data.count(), 3);
+        let records = data.records().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            records
+                .iter()
+                .map(|r| (u32::from(r.start), u32::from(r.end), r.area.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 0x00FF_FFFF, "电信"),
+                (0x0100_0000, 0x7FFF_FFFF, "联通"),
+                (0x8000_0000, u32::MAX, "电信"),
+            ]
+        );
+        // 遇到损坏的记录后停止
+        let mut buf = sample();
+        let idx_first = read_u32(&buf) as usize;
+        buf[idx_first + INDEX_SIZE + 4..idx_first + INDEX_SIZE + 7].fill(0xFF);
+        let data = QQWryData::from_bytes(buf).unwrap();
+        let records = data.records().collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert!(records[1].is_err());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let buf = sample();
+        for len in 0..buf.len() {
+            query_all(buf[..len].to_vec());
+        }
+        assert!(QQWryData::from_bytes(buf[..4].to_vec()).is_err());
+        // 索引指向文件之外
+        let mut buf = sample();
+        let idx_first = read_u32(&buf) as usize;
+        for i in (idx_first..buf.len()).step_by(INDEX_SIZE) {
+            buf[i + 4..i + 7].fill(0xFF);
+        }
+        assert!(query_all(buf)
+            .iter()
+            .all(|x| matches!(x, Err(CorruptDatabase { offset: 0xFFFFFF }))));
+    }
+
+    #[test]
+    fn test_garbage() {
+        let sample = sample();
+        for i in 0..sample.len() {
+            for x in [0x00, 0x01, 0x02, 0x7F, 0xFF] {
+                let mut buf = sample.clone();
+                buf[i] = x;
+                query_all(buf);
+            }
+        }
+        let mut rand = XorShift::new();
+        for len in [8, 16, 64, 256, 4096] {
+            for _ in 0..64 {
+                let buf = (0..len).map(|_| rand.next_u64() as u8).collect::<Vec<u8>>();
+                query_all(buf);
+            }
+        }
+        let err = CorruptDatabase { offset: 42 };
+        assert_eq!(err.to_string(), "corrupt database at offset 42");
+    }
 }
@@ -0,0 +1,321 @@
+//! 生成纯真格式的`qqwry.dat`
+//!
+//! 文件头为索引区的开始及结束位置, 之后为记录区及索引区。记录为结束IP及国家、地区,
+//! 重复的字符串通过重定向复用: 0x01表示国家及地区都与之前的记录相同,
+//! 0x02表示国家或地区指向之前写入的字符串, 字符串为GBK编码并以0结尾
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use encoding::{all::GBK, EncoderTrap, Encoding};
+
+use crate::util::write_atomic;
+
+// 索引及重定向中的记录位置为3字节
+const MAX_OFFSET: usize = 0xFF_FFFF;
+
+/// 纯真数据库生成器, 添加IP段后调用`build`生成文件内容
+///
+/// IP段无需有序, 但不能重叠; 未覆盖的IP生成国家及地区为空的记录
+#[derive(Debug, Clone, Default)]
+pub struct QQWryWriter {
+    ranges: Vec<(u32, u32, String, String)>,
+}
+
+impl QQWryWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// 添加IP段, 国家及地区须能以GBK编码
+    pub fn add(
+        &mut self,
+        start: Ipv4Addr,
+        end: Ipv4Addr,
+        country: &str,
+        area: &str,
+    ) -> io::Result<()> {
+        if start > end {
+            return Err(invalid(format!(
+                "{}-{}: start ip is greater than end ip",
+                start, end
+            )));
+        }
+        for s in [country, area] {
+            gbk(s).map_err(|_| {
+                invalid(format!("{}-{}: {} is not encodable in gbk", start, end, s))
+            })?;
+        }
+        self.ranges.push((
+            start.into(),
+            end.into(),
+            country.to_string(),
+            area.to_string(),
+        ));
+        Ok(())
+    }
+
+    /// 生成文件内容, IP段重叠或记录区超过16MiB时返回`InvalidInput`
+    pub fn build(&self) -> io::Result<Vec<u8>> {
+        let mut ranges = self.ranges.iter().collect::<Vec<_>>();
+        ranges.sort_by_key(|x| x.0);
+        for w in ranges.windows(2) {
+            if w[1].0 <= w[0].1 {
+                return Err(invalid(format!(
+                    "{}-{} overlaps {}-{}",
+                    Ipv4Addr::from(w[0].0),
+                    Ipv4Addr::from(w[0].1),
+                    Ipv4Addr::from(w[1].0),
+                    Ipv4Addr::from(w[1].1),
+                )));
+            }
+        }
+        // 补齐未覆盖的IP, 查询时不会落到相邻的记录
+        let mut records: Vec<(u32, u32, &str, &str)> = Vec::new();
+        let mut next = Some(0u32);
+        for (start, end, country, area) in ranges {
+            if let Some(n) = next.filter(|n| n < start) {
+                records.push((n, start - 1, "", ""));
+            }
+            records.push((*start, *end, country, area));
+            next = end.checked_add(1);
+        }
+        if let Some(n) = next {
+            records.push((n, u32::MAX, "", ""));
+        }
+
+        let mut buffer = vec![0u8; 8];
+        // 已写入的字符串及国家+地区的位置
+        let mut strings: HashMap<&str, usize> = HashMap::new();
+        let mut blocks: HashMap<(&str, &str), usize> = HashMap::new();
+        let mut index = Vec::with_capacity(records.len());
+        for (start, end, country, area) in records {
+            let record_offset = buffer.len();
+            index.push((start, record_offset));
+            buffer.extend_from_slice(&end.to_le_bytes());
+            if let Some(block) = blocks.get(&(country, area)) {
+                buffer.push(0x01);
+                push_u24(&mut buffer, *block)?;
+                continue;
+            }
+            blocks.insert((country, area), buffer.len());
+            match strings.get(country) {
+                Some(ptr) => {
+                    buffer.push(0x02);
+                    push_u24(&mut buffer, *ptr)?;
+                }
+                None => push_string(&mut buffer, &mut strings, country)?,
+            }
+            match strings.get(area) {
+                Some(ptr) if !area.is_empty() => {
+                    buffer.push(0x02);
+                    push_u24(&mut buffer, *ptr)?;
+                }
+                _ => push_string(&mut buffer, &mut strings, area)?,
+            }
+        }
+        if buffer.len() > MAX_OFFSET {
+            return Err(invalid("database too large".to_string()));
+        }
+        let idx_first = buffer.len();
+        for (start, record_offset) in index {
+            buffer.extend_from_slice(&start.to_le_bytes());
+            push_u24(&mut buffer, record_offset)?;
+        }
+        let idx_last = buffer.len() - 7;
+        buffer[0..4].copy_from_slice(&(idx_first as u32).to_le_bytes());
+        buffer[4..8].copy_from_slice(&(idx_last as u32).to_le_bytes());
+        Ok(buffer)
+    }
+
+    /// 生成文件, 见[`write_atomic`]
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_atomic(path.as_ref(), &self.build()?)
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn gbk(s: &str) -> Result<Vec<u8>, ()> {
+    GBK.encode(s, EncoderTrap::Strict).map_err(|_| ())
+}
+
+fn push_u24(buffer: &mut Vec<u8>, x: usize) -> io::Result<()> {
+    if x > MAX_OFFSET {
+        return Err(invalid("database too large".to_string()));
+    }
+    buffer.extend_from_slice(&(x as u32).to_le_bytes()[..3]);
+    Ok(())
+}
+
+// 写入以0结尾的GBK字符串并记录位置
+fn push_string<'a>(
+    buffer: &mut Vec<u8>,
+    strings: &mut HashMap<&'a str, usize>,
+    s: &'a str,
+) -> io::Result<()> {
+    let bytes = gbk(s).map_err(|_| invalid(format!("{} is not encodable in gbk", s)))?;
+    if !s.is_empty() {
+        strings.insert(s, buffer.len());
+    }
+    buffer.extend(bytes);
+    buffer.push(0);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::qqwry_lib::QQWryData;
+    use crate::test_support::{TempDir, XorShift};
+
+    fn ip(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = QQWryWriter::new();
+        let ranges = [
+            ("0.0.0.0", "0.255.255.255", "IANA保留地址", ""),
+            ("1.0.0.0", "1.0.0.255", "中国–广东–深圳", "电信"),
+            ("1.0.1.0", "1.0.1.255", "中国–广东–深圳", "联通"),
+            ("1.0.2.0", "1.0.2.255", "美国", "电信"),
+            ("1.0.3.0", "1.0.3.255", "中国–广东–深圳", "电信"),
+            (
+                "1.0.4.0",
+                "255.255.255.255",
+                "IANA保留地址",
+                "纯真网络2024年10月16日IP数据",
+            ),
+        ];
+        // 乱序添加
+        for (start, end, country, area) in ranges.iter().rev() {
+            writer.add(ip(start), ip(end), country, area).unwrap();
+        }
+        let buffer = writer.build().unwrap();
+        let data = QQWryData::from_bytes(buffer.clone()).unwrap();
+        assert_eq!(data.count(), ranges.len());
+        for (start, end, country, area) in ranges {
+            for x in [start, end] {
+                let r = data.query(ip(x)).unwrap();
+                assert_eq!((r.country.as_str(), r.area.as_str()), (country, area));
+                assert_eq!((r.start, r.end), (ip(start), ip(end)));
+            }
+        }
+        // 重复的国家使用0x02, 国家及地区都重复时使用0x01
+        let (idx_first, _) = data.index_range();
+        let mode = |i: usize| {
+            let idx = idx_first + i * 7;
+            let offset = u32::from_le_bytes([buffer[idx + 4], buffer[idx + 5], buffer[idx + 6], 0]);
+            buffer[offset as usize + 4]
+        };
+        assert_eq!(mode(2), 0x02);
+        assert_eq!(mode(4), 0x01);
+
+        // 写入文件后通过mmap或读入内存查询
+        let dir = TempDir::new("qqwry-writer");
+        let path = dir.join("qqwry.dat");
+        writer.write(&path).unwrap();
+        for data in [
+            QQWryData::new(&path).unwrap(),
+            QQWryData::read(&path).unwrap(),
+        ] {
+            let r = data.query(ip("1.0.3.1")).unwrap();
+            assert_eq!(
+                (r.country.as_str(), r.area.as_str()),
+                ("中国–广东–深圳", "电信")
+            );
+        }
+    }
+
+    #[test]
+    fn test_gaps() {
+        let mut writer = QQWryWriter::new();
+        writer
+            .add(ip("1.0.0.0"), ip("1.0.0.255"), "A", "a")
+            .unwrap();
+        writer
+            .add(ip("8.8.8.0"), ip("8.8.8.255"), "B", "b")
+            .unwrap();
+        let data = QQWryData::from_bytes(writer.build().unwrap()).unwrap();
+        assert_eq!(data.count(), 5);
+        for x in ["0.0.0.1", "1.0.1.0", "8.8.9.0", "255.255.255.255"] {
+            let r = data.query(ip(x)).unwrap();
+            assert_eq!((r.country.as_str(), r.area.as_str()), ("", ""));
+        }
+        assert_eq!(data.query(ip("8.8.8.8")).unwrap().country, "B");
+    }
+
+    // 随机生成IP段及从少量字符串中选取的国家、地区, 覆盖各种重定向组合
+    #[test]
+    fn test_random() {
+        let mut rng = XorShift::new();
+        let mut rand = move || rng.next_u64();
+        let countries = ["中国–北京", "中国–广东–深圳", "美国", "日本", ""];
+        let areas = ["电信", "联通", "移动", "", "CZ88.NET"];
+        for _ in 0..20 {
+            let mut writer = QQWryWriter::new();
+            let mut expected = Vec::new();
+            let mut start = 0u64;
+            while start <= u32::MAX as u64 {
+                let end = (start + rand() % (1 << 28)).min(u32::MAX as u64);
+                let country = countries[rand() as usize % countries.len()];
+                let area = areas[rand() as usize % areas.len()];
+                writer
+                    .add(
+                        Ipv4Addr::from(start as u32),
+                        Ipv4Addr::from(end as u32),
+                        country,
+                        area,
+                    )
+                    .unwrap();
+                expected.push((start as u32, end as u32, country, area));
+                start = end + 1;
+            }
+            let data = QQWryData::from_bytes(writer.build().unwrap()).unwrap();
+            let records = data.records().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(
+                records
+                    .iter()
+                    .map(|r| (
+                        r.start.into(),
+                        r.end.into(),
+                        r.country.as_str(),
+                        r.area.as_str()
+                    ))
+                    .collect::<Vec<(u32, u32, &str, &str)>>(),
+                expected
+            );
+            for (start, end, country, area) in expected {
+                let mid = start + (end - start) / 2;
+                let r = data.query(Ipv4Addr::from(mid)).unwrap();
+                assert_eq!((r.country.as_str(), r.area.as_str()), (country, area));
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut writer = QQWryWriter::new();
+        assert!(writer.add(ip("1.0.0.1"), ip("1.0.0.0"), "A", "").is_err());
+        assert!(writer.add(ip("1.0.0.0"), ip("1.0.0.1"), "😀", "").is_err());
+        writer.add(ip("1.0.0.0"), ip("1.0.0.255"), "A", "").unwrap();
+        writer.add(ip("1.0.0.255"), ip("1.0.1.0"), "B", "").unwrap();
+        assert_eq!(
+            writer.build().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! 离线数据库的存储方式
+
+use std::{fs::File, io::Read, ops::Deref, path::Path};
+
+/// 数据库文件内容, 读入内存或通过mmap映射
+pub(crate) enum Storage {
+    Memory(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mmap(memmap2::Mmap),
+}
+
+impl Storage {
+    /// 开启`mmap`特性时使用mmap, 否则读入内存
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        #[cfg(feature = "mmap")]
+        return Self::mmap(path);
+        #[cfg(not(feature = "mmap"))]
+        Self::read(path)
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let mut buffer = Vec::new();
+        File::open(path)?.read_to_end(&mut buffer)?;
+        Ok(Self::Memory(buffer))
+    }
+
+    /// 映射期间文件被原地覆盖或截断时访问会触发SIGBUS, 替换文件须使用[`crate::util::write_atomic`]
+    #[cfg(feature = "mmap")]
+    pub fn mmap<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(Self::Mmap(mmap))
+    }
+}
+
+impl Deref for Storage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Memory(x) => x,
+            #[cfg(feature = "mmap")]
+            Self::Mmap(x) => x,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Storage {
+    fn from(buffer: Vec<u8>) -> Self {
+        Self::Memory(buffer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::TempDir;
+    #[cfg(feature = "mmap")]
+    use crate::util::write_atomic;
+
+    #[test]
+    fn test_open() {
+        let dir = TempDir::new("storage");
+        let path = dir.join("data");
+        std::fs::write(&path, b"ipr").unwrap();
+        let storage = Storage::open(&path).unwrap();
+        assert_eq!(&*storage, b"ipr");
+        #[cfg(feature = "mmap")]
+        assert!(matches!(storage, Storage::Mmap(_)));
+        #[cfg(not(feature = "mmap"))]
+        assert!(matches!(storage, Storage::Memory(_)));
+        assert!(Storage::open(dir.join("missing")).is_err());
+    }
+
+    // 以write_atomic替换文件后已映射的内容不变
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap_replace() {
+        let dir = TempDir::new("storage-mmap");
+        let path = dir.join("data");
+        std::fs::write(&path, b"old").unwrap();
+        let storage = Storage::mmap(&path).unwrap();
+        write_atomic(&path, b"new!").unwrap();
+        assert_eq!(&*storage, b"old");
+        assert_eq!(&*Storage::mmap(&path).unwrap(), b"new!");
+    }
+}
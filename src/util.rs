@@ -2,10 +2,9 @@
 
 use colored::Colorize;
 
-use tokio::{
-    fs::{self},
-    io as t_io,
-};
+use crate::error::LookupError;
+
+use tokio::fs::{self};
 
 use std::{env, io, path::Path};
 use std::{io::Write, time::Duration};
@@ -33,16 +32,36 @@ pub struct WaitBlinker {
     pub handle: JoinHandle<()>,
 }
 
+impl WaitBlinker {
+    pub async fn stop(self) {
+        self.sender.send(true).ok();
+        self.handle.await.ok();
+    }
+}
+
+// 闪烁提示, stderr为终端时输出到stderr, 避免污染stdout中的查询结果
 pub fn wait_blink(msg: &str, blink_char_num: usize) -> WaitBlinker {
     let msg = msg.to_string();
+    let to_stderr = is_terminal::is_terminal(io::stderr());
+    let write = move |s: String| {
+        if to_stderr {
+            eprint!("{}", s);
+            io::stderr().flush().unwrap();
+        } else {
+            print!("{}", s);
+            io::stdout().flush().unwrap();
+        }
+    };
     let (tx, mut rx) = oneshot::channel::<bool>();
+    // 等待闪烁间隔时同时等待停止信号, 查询完成后立即清除提示
     let handle = tokio::spawn(async move {
         loop {
-            print!("{}", format!("\r{}", msg).green());
-            io::stdout().flush().unwrap();
-            sleep(Duration::from_millis(120)).await;
-            print!(
-                "{}",
+            write(format!("\r{}", msg).green().to_string());
+            tokio::select! {
+                _ = &mut rx => break,
+                _ = sleep(Duration::from_millis(120)) => {}
+            }
+            write(
                 format!(
                     "\r{}{}",
                     msg.chars()
@@ -51,33 +70,51 @@ pub fn wait_blink(msg: &str, blink_char_num: usize) -> WaitBlinker {
                     " ".repeat(blink_char_num),
                 )
                 .green()
+                .to_string(),
             );
-            io::stdout().flush().unwrap();
-            sleep(Duration::from_millis(50)).await;
-            if rx.try_recv().is_ok() {
-                clear_current_line();
-                break;
+            tokio::select! {
+                _ = &mut rx => break,
+                _ = sleep(Duration::from_millis(50)) => {}
             }
         }
+        // 使用 ANSI 转义序列清除行并将光标移到行首
+        write("\r\x1B[2K".to_string());
     });
     WaitBlinker { sender: tx, handle }
 }
 
-// 下载文件
+/// 先写入同目录的临时文件再重命名, 写入中断时不会留下损坏的文件,
+/// 其他进程已映射的旧文件内容也保持不变
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)
+}
+
+// 下载文件, 非2xx响应视为失败
 pub async fn download_file(download_url: &str, dest: &Path) -> Result<(), anyhow::Error> {
-    let response = reqwest::get(download_url).await?;
+    let response = reqwest::get(download_url).await?.error_for_status()?;
     let dest_dir = dest.parent().unwrap();
     if !dest_dir.exists() {
         fs::create_dir_all(dest_dir).await?;
     }
-    t_io::copy(
-        &mut response.bytes().await?.as_ref(),
-        &mut fs::File::create(dest).await?,
-    )
-    .await?;
+    let bytes = response.bytes().await?;
+    let dest = dest.to_path_buf();
+    tokio::task::spawn_blocking(move || write_atomic(&dest, &bytes)).await??;
     Ok(())
 }
 
+// 自动下载离线数据库, 请求失败为网络错误, 无法写入为数据库不存在
+pub(crate) async fn download_database(download_url: &str, dest: &Path) -> Result<(), LookupError> {
+    download_file(download_url, dest)
+        .await
+        .map_err(|e| match e.downcast::<reqwest::Error>() {
+            Ok(e) => LookupError::Network(e.to_string()),
+            Err(_) => LookupError::DatabaseMissing(dest.to_path_buf()),
+        })
+}
+
 pub fn replace_home(p: &str) -> String {
     if p.starts_with('~') {
         let home = env::home_dir().unwrap();
@@ -99,3 +136,70 @@ pub fn padding_ipv6(ip: &str) -> String {
     }
     ip.to_string()
 }
+
+// 过滤空值及占位符
+pub fn non_empty(s: &str) -> Option<String> {
+    let s = s.trim();
+    if s.is_empty() || s == "0" {
+        return None;
+    }
+    Some(s.to_string())
+}
+
+pub fn parse_coordinate(s: &str) -> Option<f64> {
+    s.trim().parse::<f64>().ok().filter(|x| x.is_finite())
+}
+
+// 在本地端口返回一次固定的HTTP响应, 返回URL
+#[cfg(test)]
+pub(crate) async fn serve_once(response: &'static str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        if let Ok((mut stream, _)) = listener.accept().await {
+            // 读完请求头后再响应
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(n) if n > 0 => request.extend_from_slice(&buf[..n]),
+                    _ => break,
+                }
+            }
+            stream.write_all(response.as_bytes()).await.ok();
+            stream.shutdown().await.ok();
+        }
+    });
+    format!("http://{}/", addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[tokio::test]
+    async fn test_wait_blink() {
+        let start = std::time::Instant::now();
+        let wait = wait_blink("查询中...", 3);
+        wait.stop().await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_download_database() {
+        let dir = TempDir::new("download");
+        let dest = dir.join("qqwry.dat");
+        let url = serve_once("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await;
+        let err = download_database(&url, &dest).await.unwrap_err();
+        assert!(matches!(err, LookupError::Network(_)));
+        assert_eq!(err.exit_code(), 6);
+        assert!(!dest.exists());
+
+        let url = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndata").await;
+        download_database(&url, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"data");
+    }
+}
@@ -1,14 +1,15 @@
 //! UU 在线工具 https://uutool.cn/ipv6/
 
-use std::sync::LazyLock;
+use std::{net::IpAddr, sync::LazyLock};
 
-use anyhow::anyhow;
 use reqwest::header::{self, HeaderMap};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::{
-    util::{padding_ipv6, wait_blink},
+    error::LookupError,
+    provider::{Capabilities, LookupFuture, Provider},
+    util::{non_empty, padding_ipv6, parse_coordinate},
     IPRegion,
 };
 
@@ -85,35 +86,60 @@ static HEADERS: LazyLock<HeaderMap> = LazyLock::new(|| {
     headers
 });
 
-async fn query_ipv6(ip: &str) -> Result<UUToolResponse, anyhow::Error> {
+async fn query_ipv6(ip: IpAddr) -> Result<UUToolResponse, LookupError> {
     // 填充ipv6地址
-    let mut ip = ip.to_string();
-    ip = padding_ipv6(&ip);
+    let ip = padding_ipv6(&ip.to_string());
     let response = reqwest::Client::new()
         .post("https://api.ip77.net/ip2/v4/")
         .headers((*HEADERS).clone())
         .body(format!("ip={ip}"))
         .send()
-        .await
-        .unwrap()
+        .await?
         .json::<UUToolResponse>()
-        .await
-        .unwrap();
+        .await?;
     Ok(response)
 }
 
-pub async fn query_uutool(ip: &str) -> Result<IPRegion, anyhow::Error> {
-    let wait = wait_blink("查询中，请稍候🔎...", 3);
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UUToolProvider;
+
+impl Provider for UUToolProvider {
+    fn name(&self) -> &str {
+        "UUTOOL.CN"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ipv4: true,
+            ipv6: true,
+            offline: false,
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> LookupFuture<'_> {
+        Box::pin(query_uutool(ip))
+    }
+}
+
+pub async fn query_uutool(ip: IpAddr) -> Result<IPRegion, LookupError> {
     let res = query_ipv6(ip).await?;
-    wait.sender.send(true).unwrap();
-    wait.handle.await?;
     if res.code != 0 {
         let mut msg = "查询出错".to_string();
         if let Some(e) = res.error {
             msg = e;
         }
-        return Err(anyhow!("{}", msg));
+        return Err(LookupError::Service(msg));
     }
-    let data = res.data.unwrap();
-    Ok(IPRegion::new(ip.to_string(), data.location, Some(data.isp)))
+    let data = res.data.ok_or(LookupError::NotFound)?;
+    Ok(IPRegion {
+        country: non_empty(&data.country),
+        province: non_empty(&data.province),
+        city: non_empty(&data.city),
+        district: non_empty(&data.district),
+        latitude: parse_coordinate(&data.latitude),
+        longitude: parse_coordinate(&data.longitude),
+        time_zone: non_empty(&data.time_zone),
+        zip_code: non_empty(&data.zip_code),
+        ..IPRegion::new(ip.to_string(), data.location, non_empty(&data.isp))
+    })
 }
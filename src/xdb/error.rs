@@ -0,0 +1,56 @@
+use std::{fmt, io};
+
+use crate::xdb::searcher::IpVersion;
+
+/// 打开或查询xdb时的错误
+#[derive(Debug)]
+pub enum XdbError {
+    /// 文件无法读取
+    Io(io::Error),
+    /// 文件小于文件头及向量索引的长度
+    TooShort(usize),
+    /// 文件头、索引或数据指向的位置无效
+    Corrupt {
+        offset: usize,
+    },
+    /// 地区信息不是合法的UTF-8
+    InvalidUtf8 {
+        offset: usize,
+    },
+    InvalidIp(String),
+    /// 查询的IP类型与文件不一致, 如在IPv4的xdb中查询IPv6
+    IpVersionMismatch(IpVersion),
+    NotMatched,
+    /// 生成xdb时的IP段无效, 如结束IP小于开始IP或IP段重叠
+    InvalidSegment(String),
+}
+
+impl fmt::Display for XdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::TooShort(len) => write!(f, "database too short: {} bytes", len),
+            Self::Corrupt { offset } => write!(f, "corrupt database at offset {}", offset),
+            Self::InvalidUtf8 { offset } => write!(f, "invalid utf-8 region at offset {}", offset),
+            Self::InvalidIp(ip) => write!(f, "invalid ip: {}", ip),
+            Self::IpVersionMismatch(v) => write!(f, "the xdb only supports {}", v),
+            Self::NotMatched => write!(f, "not matched"),
+            Self::InvalidSegment(msg) => write!(f, "invalid segment: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for XdbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for XdbError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
@@ -1,9 +1,14 @@
 use std::error::Error;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 
 pub trait ToUIntIP {
     fn to_u32_ip(&self) -> Result<u32, Box<dyn Error>>;
+
+    /// 默认按IPv4处理
+    fn to_ip_addr(&self) -> Result<IpAddr, Box<dyn Error>> {
+        Ok(IpAddr::V4(Ipv4Addr::from(self.to_u32_ip()?)))
+    }
 }
 
 impl ToUIntIP for u32 {
@@ -19,6 +24,13 @@ impl ToUIntIP for &str {
         }
         Ok(self.parse::<u32>()?)
     }
+
+    fn to_ip_addr(&self) -> Result<IpAddr, Box<dyn Error>> {
+        if let Ok(ip_addr) = IpAddr::from_str(self) {
+            return Ok(ip_addr);
+        }
+        Ok(IpAddr::V4(Ipv4Addr::from(self.parse::<u32>()?)))
+    }
 }
 
 impl ToUIntIP for Ipv4Addr {
@@ -27,6 +39,29 @@ impl ToUIntIP for Ipv4Addr {
     }
 }
 
+impl ToUIntIP for Ipv6Addr {
+    fn to_u32_ip(&self) -> Result<u32, Box<dyn Error>> {
+        Err(format!("{} is not an IPv4 address", self).into())
+    }
+
+    fn to_ip_addr(&self) -> Result<IpAddr, Box<dyn Error>> {
+        Ok(IpAddr::V6(*self))
+    }
+}
+
+impl ToUIntIP for IpAddr {
+    fn to_u32_ip(&self) -> Result<u32, Box<dyn Error>> {
+        match self {
+            IpAddr::V4(ip) => ip.to_u32_ip(),
+            IpAddr::V6(ip) => ip.to_u32_ip(),
+        }
+    }
+
+    fn to_ip_addr(&self) -> Result<IpAddr, Box<dyn Error>> {
+        Ok(*self)
+    }
+}
+
 #[cfg(test)]
 mod test_ip {
     use super::*;
@@ -58,4 +93,15 @@ mod test_ip {
         let result = ip.to_u32_ip().unwrap();
         assert_eq!(result, 3 << 8 | 12)
     }
+
+    #[test]
+    fn test_ipv6_str() {
+        let ip = "240e::1";
+        assert!(ip.to_u32_ip().is_err());
+        assert_eq!(ip.to_ip_addr().unwrap(), IpAddr::from_str(ip).unwrap());
+        assert_eq!(
+            "12".to_ip_addr().unwrap(),
+            IpAddr::from_str("0.0.0.12").unwrap()
+        );
+    }
 }
@@ -0,0 +1,332 @@
+//! 生成xdb文件, 格式与官方maker一致: 文件头、向量索引、去重后的地区数据及索引块
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use crate::util::write_atomic;
+use crate::xdb::{
+    searcher::{HEADER_INFO_LENGTH, VECTOR_INDEX_LENGTH, VECTOR_INDEX_SIZE},
+    IpVersion, XdbError,
+};
+
+/// xdb生成器, 添加IP段后调用`build`生成文件内容
+///
+/// IP段无需有序, 但不能重叠; 未覆盖的IP查询时返回`NotMatched`
+#[derive(Debug, Clone)]
+pub struct XdbMaker {
+    ip_version: IpVersion,
+    created_at: u32,
+    segments: Vec<(u128, u128, String)>,
+}
+
+impl XdbMaker {
+    pub fn new(ip_version: IpVersion) -> Self {
+        Self {
+            ip_version,
+            created_at: 0,
+            segments: Vec::new(),
+        }
+    }
+
+    /// 文件头中的生成时间, Unix时间戳(秒)
+    pub fn with_created_at(mut self, created_at: u32) -> Self {
+        self.created_at = created_at;
+        self
+    }
+
+    pub fn ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    /// 已添加的IP段数量, 生成时跨前两个字节的IP段会被拆分
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// 生成的索引块数量, 即拆分后的IP段数量
+    pub fn segment_count(&self) -> usize {
+        let shift = self.ip_version.bytes() as u32 * 8 - 16;
+        self.segments
+            .iter()
+            .map(|(start, end, _)| ((end >> shift) - (start >> shift) + 1) as usize)
+            .sum()
+    }
+
+    /// 添加IP段, IP类型须与生成器一致
+    pub fn add(&mut self, start_ip: IpAddr, end_ip: IpAddr, region: &str) -> Result<(), XdbError> {
+        let start = self.to_u128(start_ip)?;
+        let end = self.to_u128(end_ip)?;
+        if start > end {
+            return Err(XdbError::InvalidSegment(format!(
+                "{}-{}: start ip is greater than end ip",
+                start_ip, end_ip
+            )));
+        }
+        if region.len() > u16::MAX as usize {
+            return Err(XdbError::InvalidSegment(format!(
+                "{}-{}: region is too long",
+                start_ip, end_ip
+            )));
+        }
+        self.segments.push((start, end, region.to_string()));
+        Ok(())
+    }
+
+    fn to_u128(&self, ip: IpAddr) -> Result<u128, XdbError> {
+        match (ip, self.ip_version) {
+            (IpAddr::V4(ip), IpVersion::V4) => Ok(u32::from(ip) as u128),
+            (IpAddr::V6(ip), IpVersion::V6) => Ok(u128::from(ip)),
+            _ => Err(XdbError::IpVersionMismatch(self.ip_version)),
+        }
+    }
+
+    fn to_ip_addr(&self, ip: u128) -> IpAddr {
+        match self.ip_version {
+            IpVersion::V4 => IpAddr::V4(Ipv4Addr::from(ip as u32)),
+            IpVersion::V6 => IpAddr::V6(Ipv6Addr::from(ip)),
+        }
+    }
+
+    fn encode_ip(&self, ip: u128, buffer: &mut Vec<u8>) {
+        match self.ip_version {
+            IpVersion::V4 => buffer.extend_from_slice(&(ip as u32).to_le_bytes()),
+            IpVersion::V6 => buffer.extend_from_slice(&ip.to_be_bytes()),
+        }
+    }
+
+    /// 生成xdb文件内容, IP段重叠或文件超过4GiB时返回`InvalidSegment`
+    pub fn build(&self) -> Result<Vec<u8>, XdbError> {
+        if self.segments.is_empty() {
+            return Err(XdbError::InvalidSegment("no segments".to_string()));
+        }
+        let mut segments = self.segments.iter().collect::<Vec<_>>();
+        segments.sort_by_key(|x| x.0);
+        for w in segments.windows(2) {
+            if w[1].0 <= w[0].1 {
+                return Err(XdbError::InvalidSegment(format!(
+                    "{}-{} overlaps {}-{}",
+                    self.to_ip_addr(w[0].0),
+                    self.to_ip_addr(w[0].1),
+                    self.to_ip_addr(w[1].0),
+                    self.to_ip_addr(w[1].1),
+                )));
+            }
+        }
+        // 向量索引取前两个字节, 跨越的IP段按块拆分
+        let shift = self.ip_version.bytes() as u32 * 8 - 16;
+        let mut blocks = Vec::new();
+        for (start, end, region) in segments {
+            let mut s = *start;
+            loop {
+                let e = (*end).min(s | ((1u128 << shift) - 1));
+                blocks.push((s, e, region.as_str()));
+                if e == *end {
+                    break;
+                }
+                s = e + 1;
+            }
+        }
+
+        let mut buffer = vec![0u8; HEADER_INFO_LENGTH + VECTOR_INDEX_LENGTH];
+        // 相同的地区只保存一份
+        let mut data_ptrs = HashMap::new();
+        for (_, _, region) in &blocks {
+            if !data_ptrs.contains_key(region) {
+                data_ptrs.insert(*region, buffer.len());
+                buffer.extend_from_slice(region.as_bytes());
+            }
+        }
+        let segment_index_size = self.ip_version.segment_index_size();
+        let start_index_ptr = buffer.len();
+        let end_index_ptr = start_index_ptr + (blocks.len() - 1) * segment_index_size;
+        if end_index_ptr + segment_index_size > u32::MAX as usize {
+            return Err(XdbError::InvalidSegment("database too large".to_string()));
+        }
+        // 每个向量索引的开始及结束索引块
+        let mut vector_index: Vec<Option<(usize, usize)>> =
+            vec![None; VECTOR_INDEX_LENGTH / VECTOR_INDEX_SIZE];
+        for (s, e, region) in &blocks {
+            let ptr = buffer.len();
+            self.encode_ip(*s, &mut buffer);
+            self.encode_ip(*e, &mut buffer);
+            buffer.extend_from_slice(&(region.len() as u16).to_le_bytes());
+            buffer.extend_from_slice(&(data_ptrs[region] as u32).to_le_bytes());
+            let idx = (*s >> shift) as usize;
+            let first = vector_index[idx].map_or(ptr, |x| x.0);
+            vector_index[idx] = Some((first, ptr));
+        }
+        // 未覆盖的向量索引与官方maker一致写入(0, 0), 查询时返回`NotMatched`
+        for (i, x) in vector_index.iter().enumerate() {
+            let (first, last) = x.unwrap_or((0, 0));
+            let offset = HEADER_INFO_LENGTH + i * VECTOR_INDEX_SIZE;
+            buffer[offset..offset + 4].copy_from_slice(&(first as u32).to_le_bytes());
+            buffer[offset + 4..offset + 8].copy_from_slice(&(last as u32).to_le_bytes());
+        }
+
+        // IPv4使用旧版(version 2)格式, 与官方ip2region.xdb一致
+        let (version, ip_flag) = match self.ip_version {
+            IpVersion::V4 => (2u16, 0u16),
+            IpVersion::V6 => (3, 6),
+        };
+        buffer[0..2].copy_from_slice(&version.to_le_bytes());
+        // 索引策略: 向量索引
+        buffer[2..4].copy_from_slice(&1u16.to_le_bytes());
+        buffer[4..8].copy_from_slice(&self.created_at.to_le_bytes());
+        buffer[8..12].copy_from_slice(&(start_index_ptr as u32).to_le_bytes());
+        buffer[12..16].copy_from_slice(&(end_index_ptr as u32).to_le_bytes());
+        buffer[16..18].copy_from_slice(&ip_flag.to_le_bytes());
+        Ok(buffer)
+    }
+
+    /// 生成xdb文件, 见[`write_atomic`]
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), XdbError> {
+        write_atomic(path.as_ref(), &self.build()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::xdb::XdbSearcher;
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    fn maker(ip_version: IpVersion, ranges: &[(&str, &str, &str)]) -> XdbMaker {
+        let mut maker = XdbMaker::new(ip_version).with_created_at(1_700_000_000);
+        for (start, end, region) in ranges {
+            maker.add(addr(start), addr(end), region).unwrap();
+        }
+        maker
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let ranges = [
+            ("1.3.1.0", "255.255.255.255", "美国|0|0|0|0"),
+            ("0.0.0.0", "1.0.255.255", "0|0|0|内网IP|内网IP"),
+            ("1.1.0.0", "1.3.0.255", "中国|0|广东省|深圳市|电信"),
+        ];
+        let searcher =
+            XdbSearcher::from_bytes(maker(IpVersion::V4, &ranges).build().unwrap()).unwrap();
+        let header = searcher.header();
+        assert_eq!(
+            (header.version, header.created_at, header.ip_version),
+            (2, 1_700_000_000, IpVersion::V4)
+        );
+        for (start, end, region) in ranges {
+            for ip in [start, end] {
+                let segment = searcher.search_ip(addr(ip)).unwrap();
+                assert_eq!(segment.region, region);
+            }
+        }
+        // 跨/16的IP段被拆分, 相邻的索引块首尾相接
+        let segments = searcher.segments().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(segments.len(), header.count());
+        assert_eq!(
+            segments.len(),
+            maker(IpVersion::V4, &ranges).segment_count()
+        );
+        assert_eq!(segments.len(), 256 + 3 + (65536 - 259) + 1);
+        assert_eq!(segments[0].start_ip, addr("0.0.0.0"));
+        assert_eq!(segments.last().unwrap().end_ip, addr("255.255.255.255"));
+    }
+
+    #[test]
+    fn test_dedupe_region() {
+        let one = maker(IpVersion::V4, &[("1.0.0.0", "1.0.255.255", "中国")]);
+        let many = maker(IpVersion::V4, &[("1.0.0.0", "1.255.255.255", "中国")]);
+        let one = one.build().unwrap();
+        let many = many.build().unwrap();
+        // 拆分出的256个索引块共用一份地区数据
+        assert_eq!(
+            many.len() - one.len(),
+            255 * IpVersion::V4.segment_index_size()
+        );
+    }
+
+    #[test]
+    fn test_gaps() {
+        let searcher = XdbSearcher::from_bytes(
+            maker(
+                IpVersion::V4,
+                &[
+                    ("1.0.0.0", "1.0.0.255", "A"),
+                    ("1.0.2.0", "1.0.2.255", "B"),
+                    ("8.8.8.0", "8.8.8.255", "C"),
+                ],
+            )
+            .build()
+            .unwrap(),
+        )
+        .unwrap();
+        for ip in [
+            "0.0.0.1",
+            "1.0.1.1",
+            "1.0.3.0",
+            "2.0.0.0",
+            "8.8.9.0",
+            "255.255.255.255",
+        ] {
+            assert!(matches!(
+                searcher.search_ip(addr(ip)),
+                Err(XdbError::NotMatched)
+            ));
+        }
+        assert_eq!(searcher.search("1.0.2.1").unwrap(), "B");
+        assert_eq!(searcher.search("8.8.8.8").unwrap(), "C");
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let ranges = [
+            ("::", "240d:ffff:ffff:ffff:ffff:ffff:ffff:ffff", "0|0|0|0"),
+            ("240e::", "240f:ff::ffff", "中国|广东省|深圳市|电信"),
+            (
+                "240f:ff::1:0",
+                "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+                "美国|0|0|0",
+            ),
+        ];
+        let searcher =
+            XdbSearcher::from_bytes(maker(IpVersion::V6, &ranges).build().unwrap()).unwrap();
+        assert_eq!(searcher.ip_version(), IpVersion::V6);
+        assert_eq!(searcher.version(), 3);
+        for (start, end, region) in ranges {
+            for ip in [start, end] {
+                assert_eq!(searcher.search_ip(addr(ip)).unwrap().region, region);
+            }
+        }
+        assert_eq!(
+            searcher.search("240e:3b7::1").unwrap(),
+            "中国|广东省|深圳市|电信"
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut maker = XdbMaker::new(IpVersion::V4);
+        assert!(matches!(maker.build(), Err(XdbError::InvalidSegment(_))));
+        assert!(matches!(
+            maker.add(addr("1.0.0.1"), addr("1.0.0.0"), "A"),
+            Err(XdbError::InvalidSegment(_))
+        ));
+        assert!(matches!(
+            maker.add(addr("::1"), addr("::2"), "A"),
+            Err(XdbError::IpVersionMismatch(IpVersion::V4))
+        ));
+        maker.add(addr("1.0.0.0"), addr("1.0.0.255"), "A").unwrap();
+        maker
+            .add(addr("1.0.0.255"), addr("1.0.1.255"), "B")
+            .unwrap();
+        assert!(matches!(maker.build(), Err(XdbError::InvalidSegment(_))));
+    }
+}
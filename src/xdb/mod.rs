@@ -1,5 +1,9 @@
 //! copy from [https://github.com/lionsoul2014/ip2region/tree/master/binding/rust/xdb](https://github.com/lionsoul2014/ip2region/tree/master/binding/rust/xdb)
+mod error;
 mod ip_value;
+mod maker;
+pub use self::error::XdbError;
 pub use self::ip_value::ToUIntIP;
+pub use self::maker::XdbMaker;
 pub mod searcher;
-pub use searcher::{search_by_ip, searcher_init};
+pub use searcher::{CachePolicy, Header, IpVersion, Segment, Segments, XdbSearcher};
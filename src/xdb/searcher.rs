@@ -1,167 +1,871 @@
-use std::error::Error;
-use std::fmt::Display;
+use std::borrow::Cow;
+use std::fmt::{self, Display};
 use std::fs::File;
-use std::io::Read;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::Path;
 
-use once_cell::sync::OnceCell;
+use clap::ValueEnum;
 
-use crate::xdb::ToUIntIP;
+use crate::{
+    storage::Storage,
+    xdb::{ToUIntIP, XdbError},
+};
 
-const HEADER_INFO_LENGTH: usize = 256;
+pub(super) const HEADER_INFO_LENGTH: usize = 256;
 const VECTOR_INDEX_COLS: usize = 256;
-const VECTOR_INDEX_SIZE: usize = 8;
-const SEGMENT_INDEX_SIZE: usize = 14;
-const VECTOR_INDEX_LENGTH: usize = 512 * 1024;
-
-const XDB_FILEPATH_ENV: &str = "XDB_FILEPATH";
-
-static CACHE: OnceCell<Vec<u8>> = OnceCell::new();
-
-/// check https://mp.weixin.qq.com/s/ndjzu0BgaeBmDOCw5aqHUg for details
-pub fn search_by_ip<T>(ip: T) -> Result<String, Box<dyn Error>>
-where
-    T: ToUIntIP + Display,
-{
-    let ip = ip.to_u32_ip()?;
-    let il0 = ((ip >> 24) & 0xFF) as usize;
-    let il1 = ((ip >> 16) & 0xFF) as usize;
-    let idx = VECTOR_INDEX_SIZE * (il0 * VECTOR_INDEX_COLS + il1);
-    let start_point = idx;
-    let vector_cache = get_vector_index_cache();
-    let start_ptr = get_block_by_size(vector_cache, start_point, 4);
-    let end_ptr = get_block_by_size(vector_cache, start_point + 4, 4);
-    let mut left: usize = 0;
-    let mut right: usize = (end_ptr - start_ptr) / SEGMENT_INDEX_SIZE;
-
-    while left <= right {
-        let mid = (left + right) >> 1;
-        let offset = start_ptr + mid * SEGMENT_INDEX_SIZE;
-        let buffer_ip_value = &get_full_cache()[offset..offset + SEGMENT_INDEX_SIZE];
-        let start_ip = get_block_by_size(buffer_ip_value, 0, 4);
-        if ip < (start_ip as u32) {
-            right = mid - 1;
-        } else if ip > (get_block_by_size(buffer_ip_value, 4, 4) as u32) {
-            left = mid + 1;
-        } else {
-            let data_length = get_block_by_size(buffer_ip_value, 8, 2);
-            let data_offset = get_block_by_size(buffer_ip_value, 10, 4);
-            let result = String::from_utf8(
-                get_full_cache()[data_offset..(data_offset + data_length)].to_vec(),
-            );
-            return Ok(result?);
+pub(super) const VECTOR_INDEX_SIZE: usize = 8;
+pub(super) const VECTOR_INDEX_LENGTH: usize = 512 * 1024;
+
+/// xdb的缓存策略, 与官方binding一致
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum CachePolicy {
+    /// 缓存整个文件, 查询最快
+    #[default]
+    Content,
+    /// 只缓存512KiB的向量索引, 索引块及数据从文件读取
+    VectorIndex,
+    /// 不缓存, 全部从文件读取
+    File,
+}
+
+/// xdb文件的IP类型, 由文件头第16字节起的u16表示, 旧版文件为0, 即IPv4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    // 索引块中IP的字节数, IPv4为小端序, IPv6为大端序
+    pub(super) fn bytes(&self) -> usize {
+        match self {
+            Self::V4 => 4,
+            Self::V6 => 16,
         }
     }
-    Err("not matched".into())
+
+    // 索引块: 开始IP、结束IP、数据长度(2字节)及数据位置(4字节)
+    pub(super) fn segment_index_size(&self) -> usize {
+        self.bytes() * 2 + 6
+    }
 }
 
-/// it will check ../data/ip2region.xdb, ../../data/ip2region.xdb, ../../../data/ip2region.xdb
-fn default_detect_xdb_file() -> Result<String, Box<dyn Error>> {
-    let prefix = "../".to_owned();
-    for recurse in 1..4 {
-        let filepath = prefix.repeat(recurse) + "data/ip2region.xdb";
-        if Path::new(filepath.as_str()).exists() {
-            return Ok(filepath);
+impl fmt::Display for IpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::V4 => write!(f, "IPv4"),
+            Self::V6 => write!(f, "IPv6"),
         }
     }
-    Err("default filepath not find the xdb file, so you must set xdb_filepath".into())
 }
 
-#[inline]
-pub fn get_block_by_size(bytes: &[u8], offset: usize, length: usize) -> usize {
-    let mut result: usize = 0;
-    for (index, value) in bytes[offset..offset + length].iter().enumerate() {
-        result += usize::from(*value) << (index << 3);
+/// 命中的索引块, 包含IP段及地区信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub start_ip: IpAddr,
+    pub end_ip: IpAddr,
+    pub region: String,
+}
+
+/// xdb文件头中的字段, 其余字节保留
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// 格式版本, 2为旧版IPv4格式, 3起支持IPv6
+    pub version: u16,
+    /// 索引策略, 1为向量索引
+    pub index_policy: u16,
+    /// 生成时间, Unix时间戳(秒)
+    pub created_at: u32,
+    pub start_index_ptr: u32,
+    pub end_index_ptr: u32,
+    pub ip_version: IpVersion,
+}
+
+impl Header {
+    /// 索引块的数量, 即IP段的数量
+    pub fn count(&self) -> usize {
+        (self.end_index_ptr - self.start_index_ptr) as usize / self.ip_version.segment_index_size()
+            + 1
     }
-    result
 }
 
-pub fn searcher_init(xdb_filepath: Option<String>) {
-    let xdb_filepath = xdb_filepath.unwrap_or_else(|| default_detect_xdb_file().unwrap());
-    std::env::set_var(XDB_FILEPATH_ENV, xdb_filepath);
-    CACHE.get_or_init(load_file);
+/// 所有索引块的迭代器, 见[`XdbSearcher::segments`]
+pub struct Segments<'a> {
+    searcher: &'a XdbSearcher,
+    // 下一个索引块的位置
+    offset: usize,
+}
+
+impl Iterator for Segments<'_> {
+    type Item = Result<Segment, XdbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = &self.searcher.header;
+        if self.offset > header.end_index_ptr as usize {
+            return None;
+        }
+        let segment_index_size = header.ip_version.segment_index_size();
+        let res = self
+            .searcher
+            .read_block(self.offset, segment_index_size)
+            .and_then(|block| self.searcher.segment(&block));
+        self.offset = match res {
+            Ok(_) => self.offset + segment_index_size,
+            Err(_) => usize::MAX,
+        };
+        Some(res)
+    }
+}
+
+enum Source {
+    Content(Storage),
+    VectorIndex { file: File, vector_index: Vec<u8> },
+    File(File),
+}
+
+/// xdb查询器, 每个实例持有各自的数据或文件, 可同时打开多个xdb文件
+pub struct XdbSearcher {
+    source: Source,
+    // 文件长度, 打开时已校验文件头中的索引范围
+    len: usize,
+    header: Header,
+}
+
+impl fmt::Debug for XdbSearcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XdbSearcher")
+            .field("policy", &self.policy())
+            .field("version", &self.header.version)
+            .field("ip_version", &self.header.ip_version)
+            .field("size", &self.len)
+            .finish()
+    }
+}
+
+impl XdbSearcher {
+    /// 开启`mmap`特性时通过mmap加载整个xdb文件, 否则读入内存
+    pub fn new<P: AsRef<Path>>(xdb_filepath: P) -> Result<Self, XdbError> {
+        Self::from_source(Source::Content(Storage::open(xdb_filepath)?))
+    }
+
+    /// 读入内存
+    pub fn read<P: AsRef<Path>>(xdb_filepath: P) -> Result<Self, XdbError> {
+        Self::from_source(Source::Content(Storage::read(xdb_filepath)?))
+    }
+
+    /// 通过mmap加载, 多个进程共享同一份页缓存
+    #[cfg(feature = "mmap")]
+    pub fn mmap<P: AsRef<Path>>(xdb_filepath: P) -> Result<Self, XdbError> {
+        Self::from_source(Source::Content(Storage::mmap(xdb_filepath)?))
+    }
+
+    /// 按缓存策略打开xdb文件
+    pub fn with_policy<P: AsRef<Path>>(
+        xdb_filepath: P,
+        policy: CachePolicy,
+    ) -> Result<Self, XdbError> {
+        let source = match policy {
+            CachePolicy::Content => return Self::new(xdb_filepath),
+            CachePolicy::VectorIndex => {
+                let file = File::open(xdb_filepath)?;
+                let mut vector_index = vec![0; VECTOR_INDEX_LENGTH];
+                if read_exact_at(&file, &mut vector_index, HEADER_INFO_LENGTH as u64).is_err() {
+                    return Err(XdbError::TooShort(file.metadata()?.len() as usize));
+                }
+                Source::VectorIndex { file, vector_index }
+            }
+            CachePolicy::File => Source::File(File::open(xdb_filepath)?),
+        };
+        Self::from_source(source)
+    }
+
+    pub fn from_bytes(buffer: Vec<u8>) -> Result<Self, XdbError> {
+        Self::from_source(Source::Content(buffer.into()))
+    }
+
+    // 校验文件长度及文件头中索引块的范围
+    fn from_source(source: Source) -> Result<Self, XdbError> {
+        let len = match &source {
+            Source::Content(buffer) => buffer.len(),
+            Source::VectorIndex { file, .. } | Source::File(file) => {
+                file.metadata()?.len() as usize
+            }
+        };
+        if len < HEADER_INFO_LENGTH + VECTOR_INDEX_LENGTH {
+            return Err(XdbError::TooShort(len));
+        }
+        let mut searcher = Self {
+            source,
+            len,
+            header: Header {
+                version: 0,
+                index_policy: 0,
+                created_at: 0,
+                start_index_ptr: 0,
+                end_index_ptr: 0,
+                ip_version: IpVersion::V4,
+            },
+        };
+        let header = searcher.read_block(0, 18)?.into_owned();
+        let ip_version = match get_block_by_size(&header, 16, 2) {
+            0 | 4 => IpVersion::V4,
+            6 => IpVersion::V6,
+            _ => return Err(XdbError::Corrupt { offset: 16 }),
+        };
+        let segment_index_size = ip_version.segment_index_size();
+        let start_index_ptr = get_block_by_size(&header, 8, 4);
+        let end_index_ptr = get_block_by_size(&header, 12, 4);
+        if start_index_ptr < HEADER_INFO_LENGTH + VECTOR_INDEX_LENGTH
+            || start_index_ptr > end_index_ptr
+            || !(end_index_ptr - start_index_ptr).is_multiple_of(segment_index_size)
+            || end_index_ptr + segment_index_size > len
+        {
+            return Err(XdbError::Corrupt { offset: 8 });
+        }
+        searcher.header = Header {
+            version: get_block_by_size(&header, 0, 2) as u16,
+            index_policy: get_block_by_size(&header, 2, 2) as u16,
+            created_at: get_block_by_size(&header, 4, 4) as u32,
+            start_index_ptr: start_index_ptr as u32,
+            end_index_ptr: end_index_ptr as u32,
+            ip_version,
+        };
+        Ok(searcher)
+    }
+
+    /// 文件头中的格式版本, 2为旧版IPv4格式, 3起支持IPv6
+    pub fn version(&self) -> u16 {
+        self.header.version
+    }
+
+    pub fn ip_version(&self) -> IpVersion {
+        self.header.ip_version
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn policy(&self) -> CachePolicy {
+        match self.source {
+            Source::Content(_) => CachePolicy::Content,
+            Source::VectorIndex { .. } => CachePolicy::VectorIndex,
+            Source::File(_) => CachePolicy::File,
+        }
+    }
+
+    pub fn search<T>(&self, ip: T) -> Result<String, XdbError>
+    where
+        T: ToUIntIP + Display,
+    {
+        self.search_segment(ip).map(|x| x.region)
+    }
+
+    pub fn search_segment<T>(&self, ip: T) -> Result<Segment, XdbError>
+    where
+        T: ToUIntIP + Display,
+    {
+        let ip = ip
+            .to_ip_addr()
+            .map_err(|_| XdbError::InvalidIp(ip.to_string()))?;
+        self.search_ip(ip)
+    }
+
+    /// 按文件的IP类型查询, 类型不一致时返回`IpVersionMismatch`
+    ///
+    /// check https://mp.weixin.qq.com/s/ndjzu0BgaeBmDOCw5aqHUg for details
+    pub fn search_ip(&self, ip: IpAddr) -> Result<Segment, XdbError> {
+        // 向量索引取IP的前两个字节
+        let (ip, il0, il1) = match (ip, self.header.ip_version) {
+            (IpAddr::V4(ip), IpVersion::V4) => {
+                let o = ip.octets();
+                (u32::from(ip) as u128, o[0] as usize, o[1] as usize)
+            }
+            (IpAddr::V6(ip), IpVersion::V6) => {
+                let o = ip.octets();
+                (u128::from(ip), o[0] as usize, o[1] as usize)
+            }
+            _ => return Err(XdbError::IpVersionMismatch(self.header.ip_version)),
+        };
+        let bytes = self.header.ip_version.bytes();
+        let segment_index_size = self.header.ip_version.segment_index_size();
+        let idx = VECTOR_INDEX_SIZE * (il0 * VECTOR_INDEX_COLS + il1);
+        let vector_index = self.vector_index(idx)?;
+        let start_ptr = get_block_by_size(&vector_index, 0, 4);
+        let end_ptr = get_block_by_size(&vector_index, 4, 4);
+        // 官方maker对没有IP段的前缀写入(0, 0)
+        if start_ptr == 0 && end_ptr == 0 {
+            return Err(XdbError::NotMatched);
+        }
+        // 指针须在索引区内并与索引块对齐
+        let (index_start, index_end) = (
+            self.header.start_index_ptr as usize,
+            self.header.end_index_ptr as usize,
+        );
+        let aligned = |ptr: usize| (ptr - index_start).is_multiple_of(segment_index_size);
+        if start_ptr < index_start
+            || end_ptr > index_end
+            || start_ptr > end_ptr
+            || !aligned(start_ptr)
+            || !aligned(end_ptr)
+        {
+            return Err(XdbError::Corrupt {
+                offset: HEADER_INFO_LENGTH + idx,
+            });
+        }
+        let mut left: usize = 0;
+        let mut right: usize = (end_ptr - start_ptr) / segment_index_size;
+
+        while left <= right {
+            let mid = (left + right) >> 1;
+            let offset = start_ptr + mid * segment_index_size;
+            let buffer_ip_value = self.read_block(offset, segment_index_size)?;
+            let start_ip = self.decode_ip(&buffer_ip_value[..bytes]);
+            let end_ip = self.decode_ip(&buffer_ip_value[bytes..bytes * 2]);
+            if ip < start_ip {
+                if mid == 0 {
+                    break;
+                }
+                right = mid - 1;
+            } else if ip > end_ip {
+                left = mid + 1;
+            } else {
+                return self.segment(&buffer_ip_value);
+            }
+        }
+        Err(XdbError::NotMatched)
+    }
+
+    /// 按IP顺序遍历所有索引块, 遇到错误后停止
+    pub fn segments(&self) -> Segments<'_> {
+        Segments {
+            searcher: self,
+            offset: self.header.start_index_ptr as usize,
+        }
+    }
+
+    // 解析索引块并读取地区信息
+    fn segment(&self, block: &[u8]) -> Result<Segment, XdbError> {
+        let bytes = self.header.ip_version.bytes();
+        let data_length = get_block_by_size(block, bytes * 2, 2);
+        let data_offset = get_block_by_size(block, bytes * 2 + 2, 4);
+        let data = self.read_block(data_offset, data_length)?;
+        let region = String::from_utf8(data.into_owned()).map_err(|_| XdbError::InvalidUtf8 {
+            offset: data_offset,
+        })?;
+        Ok(Segment {
+            start_ip: self.to_ip_addr(self.decode_ip(&block[..bytes])),
+            end_ip: self.to_ip_addr(self.decode_ip(&block[bytes..bytes * 2])),
+            region,
+        })
+    }
+
+    fn decode_ip(&self, bytes: &[u8]) -> u128 {
+        match self.header.ip_version {
+            IpVersion::V4 => get_block_by_size(bytes, 0, 4) as u128,
+            IpVersion::V6 => bytes.iter().fold(0, |acc, x| (acc << 8) | *x as u128),
+        }
+    }
+
+    fn to_ip_addr(&self, ip: u128) -> IpAddr {
+        match self.header.ip_version {
+            IpVersion::V4 => IpAddr::V4(Ipv4Addr::from(ip as u32)),
+            IpVersion::V6 => IpAddr::V6(Ipv6Addr::from(ip)),
+        }
+    }
+
+    // 第`idx`字节开始的向量索引, 包含开始及结束的索引块指针
+    fn vector_index(&self, idx: usize) -> Result<Cow<'_, [u8]>, XdbError> {
+        match &self.source {
+            Source::VectorIndex { vector_index, .. } => {
+                Ok(Cow::Borrowed(&vector_index[idx..idx + VECTOR_INDEX_SIZE]))
+            }
+            _ => self.read_block(HEADER_INFO_LENGTH + idx, VECTOR_INDEX_SIZE),
+        }
+    }
+
+    fn read_block(&self, offset: usize, length: usize) -> Result<Cow<'_, [u8]>, XdbError> {
+        let corrupt = XdbError::Corrupt { offset };
+        let end = match offset.checked_add(length) {
+            Some(end) if end <= self.len => end,
+            _ => return Err(corrupt),
+        };
+        let file = match &self.source {
+            Source::Content(buffer) => {
+                return buffer.get(offset..end).map(Cow::Borrowed).ok_or(corrupt)
+            }
+            Source::VectorIndex { file, .. } | Source::File(file) => file,
+        };
+        let mut buf = vec![0; length];
+        match read_exact_at(file, &mut buf, offset as u64) {
+            Ok(_) => Ok(Cow::Owned(buf)),
+            // 打开后文件被截断
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(corrupt),
+            Err(e) => Err(e.into()),
+        }
+    }
 }
 
-pub fn get_vector_index_cache() -> &'static [u8] {
-    let full_cache: &'static Vec<u8> = get_full_cache();
-    &full_cache[HEADER_INFO_LENGTH..(HEADER_INFO_LENGTH + VECTOR_INDEX_LENGTH)]
+// 按位置读取, 不修改文件的读写位置, 多线程共享同一个文件
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
 }
 
-fn load_file() -> Vec<u8> {
-    let xdb_filepath =
-        std::env::var("XDB_FILEPATH").unwrap_or_else(|_| default_detect_xdb_file().unwrap());
-    let mut f = File::open(xdb_filepath).expect("file open error");
-    let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer).expect("load file error");
-    buffer
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
 }
 
-pub fn get_full_cache() -> &'static Vec<u8> {
-    CACHE.get_or_init(load_file)
+// `bytes`的长度由调用方保证
+#[inline]
+pub fn get_block_by_size(bytes: &[u8], offset: usize, length: usize) -> usize {
+    let mut result: usize = 0;
+    for (index, value) in bytes[offset..offset + length].iter().enumerate() {
+        result += usize::from(*value) << (index << 3);
+    }
+    result
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::File;
-    use std::io::Read;
     use std::net::Ipv4Addr;
     use std::str::FromStr;
+    use std::sync::Arc;
     use std::thread;
 
     use super::*;
+    use crate::test_support::{TempDir, XorShift};
+    use crate::xdb::XdbMaker;
+
+    // 按xdb格式生成数据, 跨前两个字节的区间会被拆分, 与官方maker一致
+    fn build(ip_version: IpVersion, ranges: &[(u128, u128, &str)]) -> Vec<u8> {
+        let bits = ip_version.bytes() as u32 * 8;
+        let block = u128::MAX >> (128 - bits + 16);
+        let mut segments = Vec::new();
+        for &(start, end, region) in ranges {
+            let mut s = start;
+            loop {
+                let e = end.min(s | block);
+                segments.push((s, e, region));
+                if e == end {
+                    break;
+                }
+                s = e + 1;
+            }
+        }
+        let encode = |x: u128| match ip_version {
+            IpVersion::V4 => (x as u32).to_le_bytes().to_vec(),
+            IpVersion::V6 => x.to_be_bytes().to_vec(),
+        };
+        let mut buffer = vec![0u8; HEADER_INFO_LENGTH + VECTOR_INDEX_LENGTH];
+        let mut data_ptrs = Vec::new();
+        for (_, _, region) in &segments {
+            data_ptrs.push(buffer.len());
+            buffer.extend_from_slice(region.as_bytes());
+        }
+        let index_start = buffer.len();
+        for (i, (s, e, region)) in segments.iter().enumerate() {
+            let ptr = buffer.len() as u32;
+            buffer.extend(encode(*s));
+            buffer.extend(encode(*e));
+            buffer.extend_from_slice(&(region.len() as u16).to_le_bytes());
+            buffer.extend_from_slice(&(data_ptrs[i] as u32).to_le_bytes());
+            let idx = HEADER_INFO_LENGTH + (*s >> (bits - 16)) as usize * VECTOR_INDEX_SIZE;
+            if buffer[idx..idx + 4] == [0; 4] {
+                buffer[idx..idx + 4].copy_from_slice(&ptr.to_le_bytes());
+            }
+            buffer[idx + 4..idx + 8].copy_from_slice(&ptr.to_le_bytes());
+        }
+        let index_end = buffer.len() - ip_version.segment_index_size();
+        let (version, ip_flag) = match ip_version {
+            IpVersion::V4 => (2u16, 0u16),
+            IpVersion::V6 => (3, 6),
+        };
+        buffer[0..2].copy_from_slice(&version.to_le_bytes());
+        buffer[2..4].copy_from_slice(&1u16.to_le_bytes());
+        buffer[8..12].copy_from_slice(&(index_start as u32).to_le_bytes());
+        buffer[12..16].copy_from_slice(&(index_end as u32).to_le_bytes());
+        buffer[16..18].copy_from_slice(&ip_flag.to_le_bytes());
+        buffer
+    }
+
+    fn build_xdb(ranges: &[(u32, u32, &str)]) -> Vec<u8> {
+        let ranges = ranges
+            .iter()
+            .map(|&(s, e, r)| (s as u128, e as u128, r))
+            .collect::<Vec<_>>();
+        build(IpVersion::V4, &ranges)
+    }
+
+    fn v6(s: &str) -> u128 {
+        u128::from(Ipv6Addr::from_str(s).unwrap())
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    fn ip(s: &str) -> u32 {
+        u32::from(Ipv4Addr::from_str(s).unwrap())
+    }
+
+    fn test_searcher() -> XdbSearcher {
+        XdbSearcher::from_bytes(build_xdb(&[
+            (0, ip("1.0.255.255"), "0|0|0|内网IP|内网IP"),
+            (ip("1.1.0.0"), ip("1.3.0.255"), "中国|0|广东省|深圳市|电信"),
+            (ip("1.3.1.0"), u32::MAX, "美国|0|0|0|0"),
+        ]))
+        .unwrap()
+    }
 
     ///test all types find correct
     #[test]
     fn test_multi_type_ip() {
-        searcher_init(None);
-
-        search_by_ip("2.0.0.0").unwrap();
-        search_by_ip("32").unwrap();
-        search_by_ip(4294408949).unwrap();
-        search_by_ip(Ipv4Addr::from_str("1.1.1.1").unwrap()).unwrap();
+        let searcher = test_searcher();
+        assert_eq!(
+            searcher.search("1.2.0.0").unwrap(),
+            "中国|0|广东省|深圳市|电信"
+        );
+        assert_eq!(searcher.search("32").unwrap(), "0|0|0|内网IP|内网IP");
+        assert_eq!(searcher.search(4294408949).unwrap(), "美国|0|0|0|0");
+        assert_eq!(
+            searcher
+                .search(Ipv4Addr::from_str("1.1.1.1").unwrap())
+                .unwrap(),
+            "中国|0|广东省|深圳市|电信"
+        );
     }
 
     #[test]
     fn test_match_all_ip_correct() {
-        searcher_init(None);
-        let mut file = File::open("../../../data/ip.test.txt").unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        for line in contents.split("\n") {
-            if !line.contains("|") {
-                continue;
+        let searcher = test_searcher();
+        for (start, end, region) in [
+            ("0.0.0.0", "1.0.255.255", "0|0|0|内网IP|内网IP"),
+            ("1.1.0.0", "1.3.0.255", "中国|0|广东省|深圳市|电信"),
+            ("1.3.1.0", "1.4.255.255", "美国|0|0|0|0"),
+        ] {
+            for value in (ip(start)..=ip(end)).step_by(97) {
+                assert_eq!(searcher.search(value).unwrap(), region);
+            }
+            assert_eq!(searcher.search(ip(end)).unwrap(), region);
+        }
+        assert_eq!(searcher.search(u32::MAX).unwrap(), "美国|0|0|0|0");
+        // 跨/16的区间按块拆分
+        let segment = searcher.search_segment("1.2.3.4").unwrap();
+        assert_eq!(
+            (segment.start_ip, segment.end_ip),
+            (addr("1.2.0.0"), addr("1.2.255.255"))
+        );
+        let segment = searcher.search_segment("1.3.0.1").unwrap();
+        assert_eq!(
+            (segment.start_ip, segment.end_ip),
+            (addr("1.3.0.0"), addr("1.3.0.255"))
+        );
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let searcher = XdbSearcher::from_bytes(build(
+            IpVersion::V6,
+            &[
+                (0, v6("240d:ffff:ffff:ffff:ffff:ffff:ffff:ffff"), "0|0|0|0"),
+                (v6("240e::"), v6("240f:ff::ffff"), "中国|广东省|深圳市|电信"),
+                (v6("240f:100::"), u128::MAX, "美国|0|0|0"),
+            ],
+        ))
+        .unwrap();
+        assert_eq!(searcher.ip_version(), IpVersion::V6);
+        assert_eq!(searcher.version(), 3);
+        assert_eq!(searcher.search("::1").unwrap(), "0|0|0|0");
+        assert_eq!(
+            searcher.search("240e:3b7::1").unwrap(),
+            "中国|广东省|深圳市|电信"
+        );
+        assert_eq!(searcher.search("ffff::1").unwrap(), "美国|0|0|0");
+        let segment = searcher.search_segment("240f:ff::1").unwrap();
+        assert_eq!(
+            (segment.start_ip, segment.end_ip),
+            (addr("240f::"), addr("240f:ff::ffff"))
+        );
+        assert!(matches!(
+            searcher.search("1.1.1.1"),
+            Err(XdbError::IpVersionMismatch(IpVersion::V6))
+        ));
+        assert!(matches!(
+            test_searcher().search("240e::1"),
+            Err(XdbError::IpVersionMismatch(IpVersion::V4))
+        ));
+    }
+
+    #[test]
+    fn test_segments() {
+        let searcher = test_searcher();
+        let segments = searcher.segments().collect::<Result<Vec<_>, _>>().unwrap();
+        // 跨/16的区间已被拆分
+        assert_eq!(segments.len(), searcher.header().count());
+        assert_eq!(segments[0].start_ip, addr("0.0.0.0"));
+        assert_eq!(segments.last().unwrap().end_ip, addr("255.255.255.255"));
+        for w in segments.windows(2) {
+            assert_eq!(
+                ip(&w[0].end_ip.to_string()) + 1,
+                ip(&w[1].start_ip.to_string())
+            );
+        }
+        assert_eq!(segments[1].region, "0|0|0|内网IP|内网IP");
+    }
+
+    // 地区不重复且不跨/16时, maker的输出与按格式手工生成的数据一致
+    #[test]
+    fn test_maker_compatible() {
+        let ranges = [
+            (0, ip("0.0.255.255"), "A"),
+            (ip("0.1.0.0"), ip("0.1.0.255"), "B"),
+            (ip("0.1.1.0"), ip("0.1.255.255"), "C"),
+        ];
+        let mut maker = XdbMaker::new(IpVersion::V4);
+        for (start, end, region) in ranges {
+            maker
+                .add(
+                    Ipv4Addr::from(start).into(),
+                    Ipv4Addr::from(end).into(),
+                    region,
+                )
+                .unwrap();
+        }
+        let buffer = maker.build().unwrap();
+        let expected = build_xdb(&ranges);
+        // 文件头及整个向量索引一致, 未覆盖的向量索引均为(0, 0)
+        let vector_end = HEADER_INFO_LENGTH + VECTOR_INDEX_LENGTH;
+        assert_eq!(buffer[..vector_end], expected[..vector_end]);
+        assert_eq!(buffer[vector_end..], expected[vector_end..]);
+    }
+
+    #[test]
+    fn test_multi_thread_share_searcher() {
+        let searcher = Arc::new(test_searcher());
+        let s = searcher.clone();
+        let handle = thread::spawn(move || s.search("2.2.2.2").unwrap());
+        assert_eq!(
+            searcher.search("1.1.1.1").unwrap(),
+            "中国|0|广东省|深圳市|电信"
+        );
+        assert_eq!(handle.join().unwrap(), "美国|0|0|0|0");
+    }
+
+    #[test]
+    fn test_multi_searcher() {
+        let dir = TempDir::new("xdb-test");
+        let old = dir.join("old.xdb");
+        let new = dir.join("new.xdb");
+        std::fs::write(&old, build_xdb(&[(0, u32::MAX, "old")])).unwrap();
+        std::fs::write(&new, build_xdb(&[(0, u32::MAX, "new")])).unwrap();
+        let old_searcher = XdbSearcher::new(&old).unwrap();
+        let new_searcher = XdbSearcher::read(&new).unwrap();
+        assert_eq!(old_searcher.search(123).unwrap(), "old");
+        assert_eq!(new_searcher.search(123).unwrap(), "new");
+        assert!(matches!(
+            XdbSearcher::new(dir.join("test")),
+            Err(XdbError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_cache_policy() {
+        let dir = TempDir::new("xdb-policy");
+        let path = dir.join("ip2region.xdb");
+        std::fs::write(
+            &path,
+            build_xdb(&[
+                (0, ip("1.0.255.255"), "0|0|0|内网IP|内网IP"),
+                (ip("1.1.0.0"), ip("1.3.0.255"), "中国|0|广东省|深圳市|电信"),
+                (ip("1.3.1.0"), u32::MAX, "美国|0|0|0|0"),
+            ]),
+        )
+        .unwrap();
+        for policy in [
+            CachePolicy::Content,
+            CachePolicy::VectorIndex,
+            CachePolicy::File,
+        ] {
+            let searcher = XdbSearcher::with_policy(&path, policy).unwrap();
+            assert_eq!(searcher.policy(), policy);
+            assert_eq!(searcher.search("1.0.0.1").unwrap(), "0|0|0|内网IP|内网IP");
+            assert_eq!(
+                searcher.search("1.3.0.255").unwrap(),
+                "中国|0|广东省|深圳市|电信"
+            );
+            assert_eq!(searcher.search(u32::MAX).unwrap(), "美国|0|0|0|0");
+        }
+    }
+
+    fn assert_corrupt(buffer: Vec<u8>) {
+        let searcher = match XdbSearcher::from_bytes(buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                assert!(matches!(
+                    e,
+                    XdbError::TooShort(_) | XdbError::Corrupt { .. }
+                ));
+                return;
             }
-            let ip_test_line = line.splitn(3, "|").collect::<Vec<&str>>();
-            let start_ip = Ipv4Addr::from_str(ip_test_line[0]).unwrap();
-            let end_ip = Ipv4Addr::from_str(ip_test_line[1]).unwrap();
-            for value in u32::from(start_ip)..u32::from(end_ip) + 1 {
-                let result = search_by_ip(value).unwrap();
-                assert_eq!(result.as_str(), ip_test_line[2])
+        };
+        for ip in [0, 1 << 16, ip("1.1.1.1"), ip("1.3.0.255"), u32::MAX] {
+            match searcher.search(ip) {
+                Ok(_) | Err(XdbError::NotMatched) => {}
+                Err(XdbError::Corrupt { .. } | XdbError::InvalidUtf8 { .. }) => {}
+                Err(e) => panic!("unexpected error: {}", e),
             }
         }
     }
 
     #[test]
-    fn test_multi_thread_only_load_xdb_once() {
-        searcher_init(None);
-        let handle = thread::spawn(|| {
-            let result = search_by_ip("2.2.2.2").unwrap();
-            println!("ip search in spawn: {result}");
-        });
-        let r = search_by_ip("1.1.1.1").unwrap();
-        println!("ip search in main thread: {r}");
-        handle.join().unwrap();
+    fn test_truncated() {
+        let buffer = build_xdb(&[
+            (0, ip("1.0.255.255"), "0|0|0|内网IP|内网IP"),
+            (ip("1.1.0.0"), u32::MAX, "美国|0|0|0|0"),
+        ]);
+        assert!(matches!(
+            XdbSearcher::from_bytes(buffer[..1024].to_vec()),
+            Err(XdbError::TooShort(1024))
+        ));
+        assert!(matches!(
+            XdbSearcher::from_bytes(buffer[..buffer.len() - 1].to_vec()),
+            Err(XdbError::Corrupt { offset: 8 })
+        ));
+        for len in (0..buffer.len()).step_by(4099) {
+            assert_corrupt(buffer[..len].to_vec());
+        }
+        let dir = TempDir::new("xdb-truncated");
+        let path = dir.join("ip2region.xdb");
+        std::fs::write(&path, &buffer[..HEADER_INFO_LENGTH + 100]).unwrap();
+        for policy in [
+            CachePolicy::Content,
+            CachePolicy::VectorIndex,
+            CachePolicy::File,
+        ] {
+            assert!(matches!(
+                XdbSearcher::with_policy(&path, policy),
+                Err(XdbError::TooShort(356))
+            ));
+        }
     }
 
+    // 官方maker对没有IP段的前缀写入(0, 0)的向量索引
     #[test]
-    fn test_multi_searcher_init() {
-        for _ in 0..5 {
-            thread::spawn(|| {
-                searcher_init(None);
-            });
+    fn test_gap() {
+        let buffer = build_xdb(&[
+            (ip("1.0.0.0"), ip("1.0.255.255"), "中国"),
+            (ip("3.0.0.0"), ip("3.0.0.255"), "美国"),
+        ]);
+        let searcher = XdbSearcher::from_bytes(buffer.clone()).unwrap();
+        let gap = HEADER_INFO_LENGTH + (2 * VECTOR_INDEX_COLS) * VECTOR_INDEX_SIZE;
+        assert_eq!(buffer[gap..gap + 8], [0; 8]);
+        for x in ["0.0.0.0", "2.0.0.1", "255.255.255.255"] {
+            assert!(matches!(searcher.search(x), Err(XdbError::NotMatched)));
+        }
+        assert_eq!(searcher.search("1.0.1.1").unwrap(), "中国");
+        assert_eq!(searcher.search("3.0.0.1").unwrap(), "美国");
+        // 同一前缀下未覆盖的IP
+        assert!(matches!(
+            searcher.search("3.0.1.0"),
+            Err(XdbError::NotMatched)
+        ));
+
+        let start = get_block_by_size(&buffer, 8, 4);
+        let end = get_block_by_size(&buffer, 12, 4);
+        for (start_ptr, end_ptr) in [
+            // 指向文件头
+            (0, 0x100),
+            // 索引区之外
+            (start - 14, start),
+            (end, end + 14),
+            // 未与索引块对齐
+            (start + 1, start + 1),
+            (start, start + 13),
+        ] {
+            let mut x = buffer.clone();
+            x[gap..gap + 4].copy_from_slice(&(start_ptr as u32).to_le_bytes());
+            x[gap + 4..gap + 8].copy_from_slice(&(end_ptr as u32).to_le_bytes());
+            let searcher = XdbSearcher::from_bytes(x).unwrap();
+            assert!(matches!(
+                searcher.search("2.0.0.1"),
+                Err(XdbError::Corrupt { offset }) if offset == gap
+            ));
+        }
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let buffer = build_xdb(&[
+            (0, ip("0.1.0.255"), "中国"),
+            (ip("0.1.1.0"), u32::MAX, "美国"),
+        ]);
+        // 向量索引的开始指针大于结束指针
+        let mut x = buffer.clone();
+        x[HEADER_INFO_LENGTH..HEADER_INFO_LENGTH + 4].fill(0xFF);
+        let searcher = XdbSearcher::from_bytes(x).unwrap();
+        assert!(matches!(
+            searcher.search(1),
+            Err(XdbError::Corrupt {
+                offset: HEADER_INFO_LENGTH
+            })
+        ));
+        // 向量索引指向文件之外
+        let mut x = buffer.clone();
+        x[HEADER_INFO_LENGTH + 4..HEADER_INFO_LENGTH + 8].fill(0xFF);
+        let searcher = XdbSearcher::from_bytes(x).unwrap();
+        assert!(matches!(searcher.search(1), Err(XdbError::Corrupt { .. })));
+        // 查询的IP小于第一个索引块, 原实现中`mid - 1`会溢出
+        let mut x = buffer.clone();
+        let first = HEADER_INFO_LENGTH + VECTOR_INDEX_SIZE;
+        let ptr = get_block_by_size(&x, first, 4);
+        x[ptr..ptr + 4].copy_from_slice(&ip("0.1.0.200").to_le_bytes());
+        let searcher = XdbSearcher::from_bytes(x).unwrap();
+        assert!(matches!(
+            searcher.search("0.1.0.1"),
+            Err(XdbError::NotMatched)
+        ));
+        // 数据不是UTF-8
+        let mut x = buffer.clone();
+        let data = get_block_by_size(&x, ptr + 10, 4);
+        x[data] = 0xFF;
+        let searcher = XdbSearcher::from_bytes(x).unwrap();
+        assert!(matches!(
+            searcher.search("0.1.0.1"),
+            Err(XdbError::InvalidUtf8 { .. })
+        ));
+        // 文件头中的索引范围无效
+        let mut x = buffer.clone();
+        x[12..16].fill(0xFF);
+        assert!(matches!(
+            XdbSearcher::from_bytes(x),
+            Err(XdbError::Corrupt { offset: 8 })
+        ));
+        // 随机修改索引区
+        let mut rand = XorShift::new();
+        let start = get_block_by_size(&buffer, 8, 4);
+        for _ in 0..512 {
+            let mut x = buffer.clone();
+            for _ in 0..8 {
+                let seed = rand.next_u64();
+                let i = start + (seed as usize) % (buffer.len() - start);
+                x[i] = (seed >> 32) as u8;
+            }
+            assert_corrupt(x);
         }
-        searcher_init(None);
-        searcher_init(Some(String::from("test")));
-        search_by_ip(123).unwrap();
     }
 }
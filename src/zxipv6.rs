@@ -1,10 +1,15 @@
-use std::sync::LazyLock;
+use std::{net::IpAddr, sync::LazyLock};
 
-use anyhow::anyhow;
 use reqwest::{header::HeaderMap, Client};
 use serde::{Deserialize, Serialize};
 
-use crate::{util::wait_blink, IPRegion};
+use crate::{
+    error::LookupError,
+    ip::{parse_ip, IpRange},
+    provider::{Capabilities, LookupFuture, Provider},
+    util::non_empty,
+    IPRegion,
+};
 
 static ZX_HEADERS: LazyLock<HeaderMap> = LazyLock::new(|| {
     let mut headers = HeaderMap::new();
@@ -51,7 +56,7 @@ struct Response {
     data: Option<Data>,
 }
 
-async fn _query_zxipv6(ip: &str) -> Result<Response, anyhow::Error> {
+async fn _query_zxipv6(ip: IpAddr) -> Result<Response, LookupError> {
     let url = format!("https://ip.zxinc.org/api.php?type=json&ip={}", ip);
     let res = Client::new()
         .get(url)
@@ -63,18 +68,52 @@ async fn _query_zxipv6(ip: &str) -> Result<Response, anyhow::Error> {
     Ok(res)
 }
 
-pub async fn query_zxipv6(ip: &str) -> Result<IPRegion, anyhow::Error> {
-    let wait = wait_blink("查询中，请稍候🔎...", 3);
+/// https://ip.zxinc.org 提供的IPv6在线查询
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZXIPv6Provider;
+
+impl Provider for ZXIPv6Provider {
+    fn name(&self) -> &str {
+        "ZXINC"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ipv4: false,
+            ipv6: true,
+            offline: false,
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> LookupFuture<'_> {
+        Box::pin(query_zxipv6(ip))
+    }
+}
+
+pub async fn query_zxipv6(ip: IpAddr) -> Result<IPRegion, LookupError> {
     let res = _query_zxipv6(ip).await?;
-    wait.sender.send(true).unwrap();
-    wait.handle.await?;
     if res.code != 0 {
-        return Err(anyhow!("查询出错"));
+        return Err(LookupError::Service(format!("code {}", res.code)));
+    }
+    let data = res.data.ok_or(LookupError::NotFound)?;
+    let range = match (parse_ip(&data.ip.start), parse_ip(&data.ip.end)) {
+        (Ok(start), Ok(end)) => Some(IpRange::new(start, end)),
+        _ => None,
+    };
+    // location为`country local`的组合, 拆分为地区及运营商
+    let region = data.country.replace("\t", " ");
+    if region.trim().is_empty() {
+        return Ok(IPRegion {
+            range,
+            ..IPRegion::new(ip.to_string(), data.location.replace("\t", " "), None)
+        });
     }
-    let data = res.data.unwrap();
-    Ok(IPRegion::new(
-        ip.to_string(),
-        data.location.replace("\t", " "),
-        None,
-    ))
+    Ok(IPRegion {
+        range,
+        ..IPRegion::new(
+            ip.to_string(),
+            region.trim().to_string(),
+            non_empty(&data.local.replace("\t", " ")),
+        )
+    })
 }